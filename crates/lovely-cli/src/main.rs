@@ -0,0 +1,242 @@
+use std::fs;
+use std::path::{self, Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use getargs::{Arg, Options};
+use itertools::Itertools;
+use walkdir::WalkDir;
+
+use lovely_core::dump::write_dump_at;
+use lovely_core::log::*;
+use lovely_core::patch::table::{validate_patched, PatchTable};
+use lovely_core::report::PatchReport;
+use lovely_core::LOVELY_VERSION;
+
+const HELP: &str = "\
+Usage: lovely --mod-dir <DIR> --game-dir <DIR> [OPTIONS]
+
+Apply Lovely patches to a tree of extracted game sources without launching the game.
+
+Options:
+  --mod-dir <DIR>   Mod directory to load patches from
+  --game-dir <DIR>  Directory containing the extracted game Lua sources
+  --out-dir <DIR>   Where patched sources and .json sidecars are written. Must be empty,
+                    or written by a previous run, whose contents are then removed first.
+                    Defaults to MOD_DIR/lovely/dump
+  --dump-all        Also write sources that are not targeted by any patch
+  --strict          Fail when a pattern or regex patch does not match as expected
+  -h, --help        Print this message";
+
+// Marks an out directory as written by this binary, so that later runs may delete it.
+const OUT_MARKER: &str = ".lovely-out";
+
+struct Args {
+    mod_dir: PathBuf,
+    game_dir: PathBuf,
+    out_dir: PathBuf,
+    dump_all: bool,
+    strict: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Option<Args>> {
+    let mut opts = Options::new(args.iter().map(String::as_str));
+
+    let mut mod_dir = None;
+    let mut game_dir = None;
+    let mut out_dir = None;
+    let mut dump_all = false;
+    let mut strict = false;
+
+    while let Some(opt) = opts
+        .next_arg()
+        .map_err(|e| anyhow!("Failed to parse argument: {e:?}"))?
+    {
+        let mut value = || {
+            opts.value()
+                .map(PathBuf::from)
+                .map_err(|e| anyhow!("Missing value for {opt:?}: {e:?}"))
+        };
+
+        match opt {
+            Arg::Long("mod-dir") => mod_dir = Some(value()?),
+            Arg::Long("game-dir") => game_dir = Some(value()?),
+            Arg::Long("out-dir") => out_dir = Some(value()?),
+            Arg::Long("dump-all") => dump_all = true,
+            Arg::Long("strict") => strict = true,
+            Arg::Long("help") | Arg::Short('h') => return Ok(None),
+            _ => bail!("Unexpected argument {opt:?}\n\n{HELP}"),
+        }
+    }
+
+    let mod_dir = mod_dir.with_context(|| format!("--mod-dir is required\n\n{HELP}"))?;
+    let game_dir = game_dir.with_context(|| format!("--game-dir is required\n\n{HELP}"))?;
+    let out_dir = out_dir.unwrap_or_else(|| mod_dir.join("lovely").join("dump"));
+
+    Ok(Some(Args {
+        mod_dir,
+        game_dir,
+        out_dir,
+        dump_all,
+        strict,
+    }))
+}
+
+/// Canonicalize a path which may not exist yet, through its closest existing ancestor.
+fn canonical(path: &Path) -> Result<PathBuf> {
+    let path = path::absolute(path).with_context(|| format!("Failed to resolve path {path:?}"))?;
+    for ancestor in path.ancestors() {
+        if let Ok(base) = fs::canonicalize(ancestor) {
+            return Ok(base.join(path.strip_prefix(ancestor).unwrap()));
+        }
+    }
+    Ok(path)
+}
+
+/// Create an empty out directory. A previous out directory is only deleted if this binary wrote
+/// it, or if it's the dump directory which Lovely owns anyway. Any other directory must be empty.
+fn prepare_out_dir(out_dir: &Path, owned: bool) -> Result<()> {
+    if owned && out_dir.is_dir() {
+        info!("Cleaning up out directory at {out_dir:?}");
+        fs::remove_dir_all(out_dir)
+            .with_context(|| format!("Failed to delete out directory at {out_dir:?}"))?;
+    } else if out_dir.is_dir() {
+        let mut contents = fs::read_dir(out_dir)
+            .with_context(|| format!("Failed to read out directory at {out_dir:?}"))?;
+        if contents.next().is_some() {
+            bail!(
+                "Out directory {out_dir:?} is not empty and was not written by Lovely, \
+                refusing to delete its contents"
+            );
+        }
+    }
+
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create out directory at {out_dir:?}"))?;
+    fs::write(out_dir.join(OUT_MARKER), "")
+        .with_context(|| format!("Failed to mark out directory at {out_dir:?}"))?;
+    Ok(())
+}
+
+/// Patch every Lua source within the game directory, writing the results into the out directory.
+/// Returns the number of files written.
+fn run(args: &Args) -> Result<usize> {
+    let Args {
+        mod_dir,
+        game_dir,
+        out_dir,
+        dump_all,
+        strict,
+    } = args;
+
+    if !game_dir.is_dir() {
+        bail!("Game directory at {game_dir:?} does not exist");
+    }
+
+    // Compare resolved paths, so that `.` or symlinks can't hide the game or mod directory.
+    let out = canonical(out_dir)?;
+    let mods = canonical(mod_dir)?;
+    for (kind, dir) in [("game", canonical(game_dir)?), ("mod", mods.clone())] {
+        if dir.starts_with(&out) {
+            bail!("Out directory {out_dir:?} must not be or contain the {kind} directory {dir:?}");
+        }
+    }
+
+    info!("Using mod directory at {mod_dir:?}");
+    let mut patch_table = PatchTable::load(mod_dir)?;
+    patch_table.strict = *strict;
+    let mut report = PatchReport::new(&patch_table);
+
+    let owned = out.join(OUT_MARKER).is_file() || out == mods.join("lovely").join("dump");
+    prepare_out_dir(out_dir, owned)?;
+
+    let sources = WalkDir::new(game_dir)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .map(|x| x.path().to_path_buf())
+        .filter(|x| x.is_file())
+        .filter(|x| x.extension().is_some_and(|x| x == "lua"))
+        .sorted()
+        .collect_vec();
+
+    let mut written = 0;
+    let mut invalid = 0;
+    for source in sources {
+        // Targets are the game-relative paths of sources, always separated by forward slashes.
+        let name = source
+            .strip_prefix(game_dir)
+            .unwrap_or(&source)
+            .to_string_lossy()
+            .replace('\\', "/");
+
+        if !patch_table.needs_patching(&name) && !dump_all {
+            continue;
+        }
+
+        let buffer = fs::read_to_string(&source)
+            .with_context(|| format!("Failed to read game source at {source:?}"))?;
+
+        let (patched, debug) = patch_table
+            .apply_patches(&name, &buffer)
+            .map_err(|e| anyhow!("Failed to patch '{name}': {e}"))?;
+
+        report.record(&name, &debug.results);
+        write_dump_at(out_dir, &name, &patched, &debug);
+        written += 1;
+
+        if let Err(e) = validate_patched(&name, &patched, &debug) {
+            error!("{e}");
+            invalid += 1;
+        }
+    }
+
+    let report_path = mod_dir.join("lovely").join("report.json");
+    info!("Writing patch report to {report_path:?}");
+    report.write(&report_path);
+
+    if invalid > 0 {
+        bail!("{invalid} patched sources have syntax errors, see above");
+    }
+
+    Ok(written)
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect_vec();
+    let args = match parse_args(&args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{HELP}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let log_dir = args.mod_dir.join("lovely").join("log");
+    if let Err(e) = lovely_core::log::init(&log_dir) {
+        eprintln!("Failed to initialize logger: {e:?}");
+        return ExitCode::FAILURE;
+    }
+
+    info!("Lovely {LOVELY_VERSION}");
+
+    let start = Instant::now();
+    match run(&args) {
+        Ok(written) => {
+            info!(
+                "Wrote {written} files to {:?} in {}ms",
+                args.out_dir,
+                start.elapsed().as_millis()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("{e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -5,7 +5,7 @@ use std::hint::black_box;
 
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use crop::Rope;
-use lovely_core::patch::{InsertPosition, PatternPatch, RegexPatch, Target};
+use lovely_core::patch::{InsertPosition, PatchMeta, PatternPatch, RegexPatch, Target};
 
 const SHORT_SAMPLE_SIZE: usize = 10;
 
@@ -27,108 +27,128 @@ fn configure_criterion() -> Criterion {
     criterion
 }
 
+fn compiled_patterns(mut patches: Vec<PatternPatch>) -> Vec<PatternPatch> {
+    patches.iter_mut().for_each(PatternPatch::compile);
+    patches
+}
+
+fn compiled_regexes(mut patches: Vec<RegexPatch>) -> Vec<RegexPatch> {
+    for patch in &mut patches {
+        patch.compile().unwrap();
+    }
+    patches
+}
+
 fn pattern_patches_no_match() -> &'static [PatternPatch] {
     static PATCHES: OnceLock<Vec<PatternPatch>> = OnceLock::new();
     PATCHES.get_or_init(|| {
-        vec![
+        compiled_patterns(vec![
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "ABC".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED".to_string(),
                 match_indent: false,
                 times: None,
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "XYZ\n123".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED".to_string(),
                 match_indent: false,
                 times: None,
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "function process_data(input)\n    local result = {}\n    for i, v in ipairs(input) do\n        result[i] = v * 2\n    end\n    return result\nend".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED".to_string(),
                 match_indent: false,
                 times: None,
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "if condition_one and condition_two then\n    perform_action()\n    update_state()\nelseif condition_three then\n    alternative_action()\nelse\n    default_behavior()\nend".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED".to_string(),
                 match_indent: false,
                 times: None,
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
-        ]
+        ])
     })
 }
 
 fn pattern_patches_with_match() -> &'static [PatternPatch] {
     static PATCHES: OnceLock<Vec<PatternPatch>> = OnceLock::new();
     PATCHES.get_or_init(|| {
-        vec![
+        compiled_patterns(vec![
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "NXKOO".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED".to_string(),
                 match_indent: false,
                 times: Some(1),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "NXKOONXKOO".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED".to_string(),
                 match_indent: false,
                 times: Some(5),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "NXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOO\nNXKOONXKOONXKOONXKOONXKOO".to_string(),
                 position: InsertPosition::At,
                 payload: "-- REPLACED BLOCK --\n-- END REPLACED BLOCK --".to_string(),
                 match_indent: false,
                 times: Some(1),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "NXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOO\nNXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOO\nNXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOONXKOO".to_string(),
                 position: InsertPosition::At,
                 payload: "-- COMPLEX REPLACED BLOCK --\n-- MULTIPLE LINES --\n-- END COMPLEX REPLACED BLOCK --".to_string(),
                 match_indent: false,
                 times: Some(2),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
-        ]
+        ])
     })
 }
 
 fn regex_patches_no_match() -> &'static [RegexPatch] {
     static PATCHES: OnceLock<Vec<RegexPatch>> = OnceLock::new();
     PATCHES.get_or_init(|| {
-        vec![
+        compiled_regexes(vec![
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"ABC".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -136,10 +156,11 @@ fn regex_patches_no_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: None,
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"\d+\s*[a-z]+".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -147,10 +168,11 @@ fn regex_patches_no_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: None,
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"function\s+\w+\s*\([^)]*\)\s*\n\s*local\s+\w+\s*=\s*\{[^}]*\}\s*\n\s*for\s+\w+,\s*\w+\s+in\s+ipairs\([^)]+\)\s+do".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -158,10 +180,11 @@ fn regex_patches_no_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: None,
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"if\s+\w+\s+and\s+\w+\s+then\s*\n(?:\s+\w+\([^)]*\)\s*\n)+\s*elseif\s+\w+\s+then\s*\n(?:\s+\w+\([^)]*\)\s*\n)+\s*else".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -169,18 +192,19 @@ fn regex_patches_no_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: None,
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
-        ]
+        ])
     })
 }
 
 fn regex_patches_with_match() -> &'static [RegexPatch] {
     static PATCHES: OnceLock<Vec<RegexPatch>> = OnceLock::new();
     PATCHES.get_or_init(|| {
-        vec![
+        compiled_regexes(vec![
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"NXKOO".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -188,10 +212,11 @@ fn regex_patches_with_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(1),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"NX[A-Z]{3}".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -199,10 +224,11 @@ fn regex_patches_with_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(5),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"(?:NXKOO){10}\n(?:NXKOO){5}".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -210,10 +236,11 @@ fn regex_patches_with_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(1),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"(NXKOO){20}\n(NXKOO){20}\n(NXKOO){20}".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -221,56 +248,60 @@ fn regex_patches_with_match() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(2),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
-        ]
+        ])
     })
 }
 
 fn pattern_patches_position() -> &'static [PatternPatch] {
     static PATCHES: OnceLock<Vec<PatternPatch>> = OnceLock::new();
     PATCHES.get_or_init(|| {
-        vec![
+        compiled_patterns(vec![
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "BEGINNING*".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED_BEGINNING".to_string(),
                 match_indent: false,
                 times: Some(1),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "MIDDLE*".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED_MIDDLE".to_string(),
                 match_indent: false,
                 times: Some(1),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
             PatternPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: "END*".to_string(),
                 position: InsertPosition::At,
                 payload: "REPLACED_END".to_string(),
                 match_indent: false,
                 times: Some(1),
                 overwrite: false,
-                name: None,
+                meta: PatchMeta::default(),
+                matchers: Vec::new(),
             },
-        ]
+        ])
     })
 }
 
 fn regex_patches_position() -> &'static [RegexPatch] {
     static PATCHES: OnceLock<Vec<RegexPatch>> = OnceLock::new();
     PATCHES.get_or_init(|| {
-        vec![
+        compiled_regexes(vec![
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"BEGINNING.*".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -278,10 +309,11 @@ fn regex_patches_position() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(1),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"MIDDLE.*".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -289,10 +321,11 @@ fn regex_patches_position() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(1),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
             RegexPatch {
-                target: Target::Single("sample_buffer.txt".to_string()),
+                target: Target::Single("sample_buffer.txt".into()),
                 pattern: r"END.*".to_string(),
                 position: InsertPosition::At,
                 root_capture: None,
@@ -300,9 +333,10 @@ fn regex_patches_position() -> &'static [RegexPatch] {
                 line_prepend: String::new(),
                 times: Some(1),
                 verbose: false,
-                name: None,
+                meta: PatchMeta::default(),
+                regex: None,
             },
-        ]
+        ])
     })
 }
 
@@ -0,0 +1,257 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use itertools::Itertools;
+use log::*;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::dump::PatchDebug;
+use crate::patch::table::PatchTable;
+use crate::patch::Patch;
+use crate::report::PatchResult;
+use crate::LOVELY_VERSION;
+
+// Entries which have not been used for this long are removed on startup.
+pub const CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 14);
+
+/// Patched buffers from previous launches, stored in MOD_DIR/lovely/cache. Entries are keyed by
+/// a hash of everything that affects the patched output, so a changed mod, game or var simply
+/// misses the cache instead of invalidating it.
+pub struct PatchCache {
+    dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct CacheEntry {
+    patched: String,
+    debug: PatchDebug,
+    // Patch outcomes as (position among the patches aimed at the target, matches), since
+    // indices into `PatchTable::patches` change whenever an unrelated mod does.
+    results: Vec<(usize, Option<usize>)>,
+}
+
+impl PatchCache {
+    pub fn new(mod_dir: &Path) -> Self {
+        Self {
+            dir: mod_dir.join("lovely").join("cache"),
+        }
+    }
+
+    /// Hash the target, its original buffer, the definitions of the patches aimed at it and the
+    /// vars. Returns `None` if no patch targets the buffer, in which case there is nothing to cache.
+    pub fn key(table: &PatchTable, target: &str, buffer: &str) -> Option<String> {
+        let target = target.strip_prefix('@').unwrap_or(target);
+        let relevant = table.patches_for(target);
+        if relevant.is_empty() {
+            return None;
+        }
+
+        // Length-prefix every field so that adjacent fields can't run into each other.
+        let mut hasher = Sha256::new();
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        };
+
+        field(LOVELY_VERSION.as_bytes());
+        field(target.as_bytes());
+        field(buffer.as_bytes());
+
+        for i in relevant {
+            let loaded = &table.patches[i];
+            field(serde_json::to_string(&loaded.patch).ok()?.as_bytes());
+            // Copy sources are read at load time and aren't part of the definition.
+            if let Patch::Copy(x) = &loaded.patch {
+                for contents in &x.contents {
+                    field(contents.as_bytes());
+                }
+            }
+            field(&loaded.priority.to_le_bytes());
+            field(loaded.path.to_string_lossy().as_bytes());
+            field(&[(table.strict || loaded.strict) as u8]);
+            field(loaded.game_version.as_deref().unwrap_or_default().as_bytes());
+            // Built-in vars which aren't covered by the fields above, such as after moving the
+            // mod directory.
+            for name in ["MOD_DIR", "MOD_ID", "GAME_EXE"] {
+                field(loaded.builtins.get(name).unwrap_or_default().as_bytes());
+            }
+        }
+
+        for (mod_id, name, value) in table.vars.iter().sorted() {
+            field(mod_id.as_bytes());
+            field(name.as_bytes());
+            field(value.as_bytes());
+        }
+        for (name, value) in table.runtime_vars.read().unwrap().interpolated() {
+            field(name.as_bytes());
+            field(value.as_bytes());
+        }
+
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Look up the patched output and debug info of a previous launch. Warnings and conflicts
+    /// recorded by the patches are logged again, as they would have been when patching.
+    pub fn get(&self, table: &PatchTable, target: &str, key: &str) -> Option<(String, PatchDebug)> {
+        let path = self.dir.join(key);
+        let contents = fs::read_to_string(&path).ok()?;
+        let entry: CacheEntry = match serde_json::from_str(&contents) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Ignoring unreadable patch cache entry at {path:?}: {e}");
+                return None;
+            }
+        };
+
+        // Keep the entry alive for as long as it's being used.
+        if let Err(e) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("Failed to touch patch cache entry at {path:?}: {e}");
+        }
+
+        let target = target.strip_prefix('@').unwrap_or(target);
+        let relevant = table.patches_for(target);
+        let mut debug = entry.debug;
+        debug.results = entry
+            .results
+            .into_iter()
+            .map(|(position, matches)| {
+                Some(PatchResult {
+                    patch_index: *relevant.get(position)?,
+                    matches,
+                    errors: Vec::new(),
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        for warning in debug.entries.iter().flat_map(|x| x.warnings.iter().flatten()) {
+            warn!("{warning}");
+        }
+        for conflict in &debug.conflicts {
+            conflict.log(target);
+        }
+        info!("Loaded patched '{target}' from the patch cache");
+
+        Some((entry.patched, debug))
+    }
+
+    /// Store the patched output and debug info of a buffer.
+    pub fn put(&self, table: &PatchTable, target: &str, key: &str, patched: &str, debug: &PatchDebug) {
+        // Unresolved vars are only reported when the buffer is patched.
+        if debug.results.iter().any(|x| !x.errors.is_empty()) {
+            return;
+        }
+
+        let target = target.strip_prefix('@').unwrap_or(target);
+        let relevant = table.patches_for(target);
+        let results = debug
+            .results
+            .iter()
+            .filter_map(|x| {
+                let position = relevant.iter().position(|&i| i == x.patch_index)?;
+                Some((position, x.matches))
+            })
+            .collect_vec();
+
+        let entry = json!({
+            "patched": patched,
+            "debug": debug,
+            "results": results,
+        });
+
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            warn!("Failed to create patch cache directory at {:?}: {e}", self.dir);
+            return;
+        }
+        let path = self.dir.join(key);
+        if let Err(e) = fs::write(&path, entry.to_string()) {
+            warn!("Failed to write patch cache entry to {path:?}: {e}");
+        }
+    }
+
+    /// Remove entries which have not been used within `max_age`.
+    pub fn prune(&self, max_age: Duration) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let stale = entry
+                .metadata()
+                .and_then(|x| x.modified())
+                .is_ok_and(|x| now.duration_since(x).unwrap_or_default() > max_age);
+            if stale && fs::remove_file(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+
+        if removed > 0 {
+            info!("Removed {removed} stale entries from the patch cache");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn table(mod_dir: &Path) -> PatchTable {
+        let mod_path = mod_dir.join("test-mod");
+        fs::create_dir_all(&mod_path).unwrap();
+        fs::write(
+            mod_path.join("lovely.toml"),
+            r#"
+[manifest]
+version = "1.0.0"
+priority = 0
+
+[vars]
+amount = "2"
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local y = {{lovely:amount}}"
+match_indent = true
+"#,
+        )
+        .unwrap();
+        PatchTable::load(mod_dir).unwrap()
+    }
+
+    #[test]
+    fn serves_patched_output_until_inputs_change() {
+        let mod_dir = TempDir::new().unwrap();
+        let mut table = table(mod_dir.path());
+        let cache = PatchCache::new(mod_dir.path());
+        let buffer = "local x = 1\n";
+
+        let key = PatchCache::key(&table, "@main.lua", buffer).unwrap();
+        assert!(cache.get(&table, "@main.lua", &key).is_none());
+
+        let (patched, debug) = table.apply_patches("@main.lua", buffer).unwrap();
+        cache.put(&table, "@main.lua", &key, &patched, &debug);
+
+        let (cached, cached_debug) = cache.get(&table, "@main.lua", &key).unwrap();
+        assert_eq!(cached, patched);
+        assert_eq!(cached_debug.entries.len(), debug.entries.len());
+        assert_eq!(cached_debug.results.len(), 1);
+        assert_eq!(cached_debug.results[0].patch_index, debug.results[0].patch_index);
+
+        assert_ne!(PatchCache::key(&table, "@main.lua", "local x = 2\n").unwrap(), key);
+        table.vars.insert("test-mod", "amount", "3");
+        assert_ne!(PatchCache::key(&table, "@main.lua", buffer).unwrap(), key);
+        assert!(PatchCache::key(&table, "@other.lua", buffer).is_none());
+    }
+}
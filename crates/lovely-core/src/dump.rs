@@ -1,28 +1,51 @@
 use std::fs;
 use std::path::Path;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::report::PatchResult;
 
 // Sidecar debug entry. Written to the dump dir.
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PatchDebugEntry {
     pub patch_source: PatchSource,
     pub regions: Vec<PatchRegion>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub warnings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<usize>,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DebugPatchType {
     #[serde(rename = "pattern")]
     Pattern,
     #[serde(rename = "regex")]
     Regex,
+    #[serde(rename = "function")]
+    Function,
+    #[serde(rename = "range")]
+    Range,
     #[serde(rename = "copy")]
     Copy,
+    #[serde(rename = "module")]
+    Module,
+}
+
+impl DebugPatchType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DebugPatchType::Pattern => "pattern",
+            DebugPatchType::Regex => "regex",
+            DebugPatchType::Function => "function",
+            DebugPatchType::Range => "range",
+            DebugPatchType::Copy => "copy",
+            DebugPatchType::Module => "module",
+        }
+    }
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PatchSource {
     pub file: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -30,7 +53,22 @@ pub struct PatchSource {
     pub patch_type: DebugPatchType,
 }
 
-#[derive(Serialize, Debug)]
+impl PatchSource {
+    /// Describe the patch for log and error messages.
+    pub fn describe(&self) -> String {
+        match &self.pattern {
+            Some(pattern) => format!(
+                "the {} patch from {} ('{}')",
+                self.patch_type.as_str(),
+                self.file,
+                pattern.escape_debug()
+            ),
+            None => format!("the {} patch from {}", self.patch_type.as_str(), self.file),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PatchRegion {
     pub start_line: usize,
     pub end_line: usize,
@@ -44,6 +82,11 @@ pub struct ByteRegion {
 }
 
 impl ByteRegion {
+    /// The number of bytes the edit which produced this region removed.
+    pub fn removed(&self) -> usize {
+        ((self.end - self.start) as isize - self.delta).max(0) as usize
+    }
+
     /// Adjust this region based on an edit that occurred elsewhere.
     pub fn adjust(&mut self, edit_pos: usize, delta: isize) {
         if edit_pos <= self.start {
@@ -61,9 +104,22 @@ pub struct ByteDebugEntry {
     pub patch_source: PatchSource,
     pub regions: Vec<ByteRegion>,
     pub warnings: Option<Vec<String>>,
+    // The number of matches found by pattern / regex patches, before `times` is enforced.
+    pub matches: Option<usize>,
 }
 
 impl ByteDebugEntry {
+    /// Log the warning of a patch which found nothing to apply, and record it without regions.
+    pub fn from_warning(patch_source: PatchSource, warning: String) -> Self {
+        log::warn!("{warning}");
+        ByteDebugEntry {
+            patch_source,
+            regions: Vec::new(),
+            warnings: Some(vec![warning]),
+            matches: Some(0),
+        }
+    }
+
     /// Adjust all regions in this entry based on the edit that occurred.
     pub fn adjust(&mut self, edit_pos: usize, delta: isize) {
         for region in &mut self.regions {
@@ -72,10 +128,85 @@ impl ByteDebugEntry {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    // The later patch replaced or deleted code inserted by the earlier one.
+    Overwrite,
+    // Both patches inserted code at the same place, so their order decides the result.
+    SharedAnchor,
+}
+
+impl ConflictKind {
+    /// Classify an edit against the regions inserted by an earlier patch. Copy patches are
+    /// position-independent, so they only conflict when their code is overwritten.
+    pub fn between(earlier: &[ByteRegion], edit: &ByteRegion, anchored: bool) -> Option<Self> {
+        let removed = edit.start..edit.start + edit.removed();
+        if !removed.is_empty() {
+            return earlier
+                .iter()
+                .any(|x| x.start < removed.end && removed.start < x.end)
+                .then_some(ConflictKind::Overwrite);
+        }
+
+        let shared = earlier.iter().any(|x| x.start == edit.start || x.end == edit.start);
+        (anchored && shared).then_some(ConflictKind::SharedAnchor)
+    }
+}
+
+// Sidecar conflict between the patches of two different mods.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PatchConflict {
+    pub kind: ConflictKind,
+    pub earlier: PatchSource,
+    pub later: PatchSource,
+    // Lines of the patched buffer written by the later patch's conflicting edit.
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+impl PatchConflict {
+    /// Log the conflict. Overwritten code is a warning, a shared anchor only changes order.
+    pub fn log(&self, target: &str) {
+        match self.kind {
+            ConflictKind::Overwrite => log::warn!(
+                "Patch conflict on '{target}' at lines {}-{}: {} replaced code inserted by {}",
+                self.start_line,
+                self.end_line,
+                self.later.describe(),
+                self.earlier.describe()
+            ),
+            ConflictKind::SharedAnchor => log::info!(
+                "Patch conflict on '{target}' at line {}: {} and {} insert at the same place, \
+                in that order",
+                self.start_line,
+                self.earlier.describe(),
+                self.later.describe()
+            ),
+        }
+    }
+}
+
+/// A conflict between two patches, by their indices into the `ByteDebugEntry` list and the byte
+/// region of the later edit. Converted to line numbers once the buffer is fully patched.
+#[derive(Debug)]
+pub struct ByteConflict {
+    pub kind: ConflictKind,
+    // Indices of the entries of the earlier and later patch.
+    pub earlier: usize,
+    pub later: usize,
+    pub region: ByteRegion,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PatchDebug {
     pub buffer_name: String,
     pub entries: Vec<PatchDebugEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<PatchConflict>,
+    // Per-patch outcomes, consumed by the patch report.
+    #[serde(skip)]
+    pub results: Vec<PatchResult>,
 }
 
 impl PatchDebug {
@@ -83,11 +214,33 @@ impl PatchDebug {
         Self {
             buffer_name: buffer_name.to_string(),
             entries: Vec::new(),
+            conflicts: Vec::new(),
+            results: Vec::new(),
         }
     }
 
     /// Convert from byte-based to line-based.
-    pub fn from_byte_entries(buffer_name: &str, byte_entries: Vec<ByteDebugEntry>, rope: &crop::Rope) -> Self {
+    pub fn from_byte_entries(
+        buffer_name: &str,
+        byte_entries: Vec<ByteDebugEntry>,
+        byte_conflicts: Vec<ByteConflict>,
+        rope: &crop::Rope,
+    ) -> Self {
+        let conflicts = byte_conflicts
+            .into_iter()
+            .map(|x| {
+                let start_line = rope.line_of_byte(x.region.start) + 1;
+                let end_line = rope.line_of_byte(x.region.end.saturating_sub(1)) + 1;
+                PatchConflict {
+                    kind: x.kind,
+                    earlier: byte_entries[x.earlier].patch_source.clone(),
+                    later: byte_entries[x.later].patch_source.clone(),
+                    start_line,
+                    end_line: end_line.max(start_line),
+                }
+            })
+            .collect();
+
         let entries = byte_entries
             .into_iter()
             .map(|entry| PatchDebugEntry {
@@ -101,17 +254,57 @@ impl PatchDebug {
                     })
                     .collect(),
                 warnings: entry.warnings,
+                matches: entry.matches,
             })
             .collect();
 
         Self {
             buffer_name: buffer_name.to_string(),
             entries,
+            conflicts,
+            results: Vec::new(),
         }
     }
 }
 
-/// Dump the buffer and its sidecar.
+impl PatchDebug {
+    /// Describe which patch is responsible for a line of the patched buffer. Lines outside of
+    /// every patch are attributed to the closest patch before them, since an unbalanced payload
+    /// usually surfaces as an error further down.
+    pub fn explain_line(&self, line: usize) -> String {
+        let describe = |entry: &PatchDebugEntry| entry.patch_source.describe();
+
+        let covering = self.entries.iter().rev().find(|x| {
+            x.regions
+                .iter()
+                .any(|r| r.start_line <= line && line <= r.end_line)
+        });
+        if let Some(entry) = covering {
+            return format!("Line {line} was inserted by {}", describe(entry));
+        }
+
+        let preceding = self
+            .entries
+            .iter()
+            .flat_map(|x| x.regions.iter().map(move |r| (r, x)))
+            .filter(|(r, _)| r.end_line < line)
+            .max_by_key(|(r, _)| r.end_line);
+        match preceding {
+            Some((region, entry)) => format!(
+                "Line {line} is not part of a patch, the closest patched lines before it \
+                ({}-{}) were inserted by {}",
+                region.start_line,
+                region.end_line,
+                describe(entry)
+            ),
+            None => format!(
+                "Line {line} is not part of a patch and no patch inserted lines before it"
+            ),
+        }
+    }
+}
+
+/// Dump the buffer and its sidecar into MOD_DIR/lovely/DIR_NAME.
 pub fn write_dump(
     mod_dir: &Path,
     dir_name: &str,
@@ -119,11 +312,16 @@ pub fn write_dump(
     buffer: &str,
     debug: &PatchDebug,
 ) {
+    write_dump_at(&mod_dir.join("lovely").join(dir_name), name, buffer, debug);
+}
+
+/// Dump the buffer and its sidecar into an arbitrary dump directory.
+pub fn write_dump_at(dump_dir: &Path, name: &str, buffer: &str, debug: &PatchDebug) {
     if name.chars().count() > 100 {
         return;
     }
 
-    let dump_path = mod_dir.join("lovely").join(dir_name).join(name);
+    let dump_path = dump_dir.join(name);
     if fs::exists(&dump_path).unwrap_or(false) {
         return;
     }
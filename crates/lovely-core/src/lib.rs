@@ -1,13 +1,12 @@
 #![allow(non_upper_case_globals)]
 
 use core::slice;
-use std::collections::{HashMap, HashSet};
 use std::ffi::{c_int, CStr};
 use std::panic;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, OnceLock, RwLock};
-use std::time::Instant;
-use std::{env, fs};
+use std::sync::{Arc, OnceLock, RwLock, RwLockWriteGuard, TryLockError};
+use std::time::{Duration, Instant};
+use std::{env, fs, process, thread};
 
 use log::*;
 
@@ -16,19 +15,43 @@ use itertools::Itertools;
 use patch::{ModulePatch, Patch};
 use regex_lite::Regex;
 
-use sys::{check_lua_string, LuaFunc, LuaLib, LuaState, LuaStateTrait, LUA};
+use sys::{check_lua_string, LuaFunc, LuaLib, LuaState, LuaStateTrait, Pushable, LUA};
 
-use crate::patch::Target;
+use crate::cache::PatchCache;
+use crate::patch::table::explain_syntax_error;
+use crate::patch::{Target, TargetName, TargetSet};
 use crate::dump::{PatchDebug, write_dump};
+use crate::report::{PatchReport, PatchResult};
+use crate::runtime_vars::{RuntimeValue, RuntimeVars};
+use crate::watch::ReloadEvent;
 
+pub mod cache;
 pub mod chunk_vec_cursor;
 pub mod dump;
 pub mod log;
+pub mod lua_parser;
 pub mod patch;
+pub mod report;
+pub mod runtime_vars;
 pub mod sys;
+pub mod watch;
 
 pub const LOVELY_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Printed by --help, followed by the arguments declared by patch files.
+const LOVELY_HELP: &str = "Lovely options:
+  --mod-dir <PATH>                Load mods from this directory
+  --vanilla                       Run the game without mods
+  --disable-console               Don't open the log console (Windows)
+  --lovely-strict                 Treat unmatched patches as errors
+  --lovely-watch                  Reload patches when the mod directory changes
+  --lovely-no-cache               Don't cache patched buffers
+  --help                          Print this help and exit without starting the game
+";
+
+// Returned by loadbuffer when the chunk has a syntax error.
+const LUA_ERRSYNTAX: u32 = 3;
+
 pub static RUNTIME: OnceLock<Lovely> = OnceLock::new();
 
 type LoadBuffer =
@@ -36,29 +59,59 @@ type LoadBuffer =
 
 unsafe extern "C" fn reload_patches(state: *mut LuaState) -> c_int {
     let lovely = &RUNTIME.get().unwrap();
-    let result = PatchTable::load(&lovely.mod_dir);
-    let new_table = match result {
-        Ok(t) => t,
-        Err(e) => {
-            state.push(false);
-            state.push(format!("{:?}", e));
-            return 2;
-        }
-    };
-    let binding = Arc::clone(&lovely.patch_table);
-    let mut patch_table = binding.write().unwrap();
-    *patch_table = new_table;
+    if let Err(e) = lovely.reload_patches() {
+        state.push(false);
+        state.push(format!("{:?}", e));
+        return 2;
+    }
     state.push(true);
     1
 }
 
+unsafe extern "C" fn poll_reload(state: *mut LuaState) -> c_int {
+    let lovely = &RUNTIME.get().unwrap();
+    let Some(event) = lovely.reload_event.write().unwrap().take() else {
+        return 0;
+    };
+
+    // Re-inject the affected modules into package.preload and drop them from package.loaded,
+    // so that the next `require` picks up their new contents.
+    let patch_table = lovely.patch_table.read().unwrap();
+    let module_patches = patch_table
+        .patches
+        .iter()
+        .filter_map(|x| match &x.patch {
+            Patch::Module(patch) => Some((patch, x.priority, &x.path)),
+            _ => None,
+        })
+        .filter(|(x, _, _)| !x.load_now && event.modules.contains(&x.name))
+        .sorted_by_key(|(_, prio, _)| *prio);
+
+    for (patch, _, path) in module_patches {
+        // Failures are logged and replace the preload with an error, which `require` surfaces.
+        let _ = patch.apply("", state, path);
+        sys::unload_module(state, &patch.name);
+    }
+    drop(patch_table);
+
+    event.push(state);
+    1
+}
+
+unsafe extern "C" fn get_report(state: *mut LuaState) -> c_int {
+    let lovely = &RUNTIME.get().unwrap();
+    let report = lovely.report.read().unwrap();
+    Pushable::push(report.entries.as_slice(), state);
+    1
+}
+
 unsafe extern "C" fn getvar(state: *mut LuaState) -> c_int {
     let key = check_lua_string(state, 1);
     let lovely = &RUNTIME.get().unwrap();
     let vars = lovely.lua_vars.read().unwrap();
     let val = vars.get(&key);
     if let Some(val) = val {
-        state.push(val);
+        val.push(state);
         return 1;
     }
     0
@@ -66,10 +119,20 @@ unsafe extern "C" fn getvar(state: *mut LuaState) -> c_int {
 
 unsafe extern "C" fn setvar(state: *mut LuaState) -> c_int {
     let key = check_lua_string(state, 1);
-    let val = check_lua_string(state, 2);
+    let val = match RuntimeValue::read(state, 2) {
+        Ok(x) => x,
+        Err(e) => {
+            state.push(format!("Failed to set var '{key}': {e}"));
+            // lua_error doesn't return, so nothing is dropped past this point.
+            drop((key, e));
+            return sys::lua_error(state);
+        }
+    };
+    // Opt in to interpolating the var into chunks loaded afterwards.
+    let interpolate = sys::lua_toboolean(state, 3) != 0;
     let lovely = &RUNTIME.get().unwrap();
     let mut vars = lovely.lua_vars.write().unwrap();
-    vars.insert(key, val);
+    vars.set(&key, val, interpolate);
     0
 }
 
@@ -88,10 +151,16 @@ unsafe extern "C" fn removevar(state: *mut LuaState) -> c_int {
 pub struct Lovely {
     pub mod_dir: PathBuf,
     pub is_vanilla: bool,
+    pub strict: bool,
     loadbuffer: &'static LoadBuffer,
     patch_table: Arc<RwLock<PatchTable>>,
     dump_all: bool,
-    lua_vars: Arc<RwLock<HashMap<String, String>>>,
+    lua_vars: Arc<RwLock<RuntimeVars>>,
+    report: Arc<RwLock<PatchReport>>,
+    // Changes picked up by the watcher which Lua has not polled yet.
+    reload_event: Arc<RwLock<Option<ReloadEvent>>>,
+    // Patched output of previous launches. `None` if disabled with `--lovely-no-cache`.
+    cache: Option<PatchCache>,
 }
 
 impl Lovely {
@@ -135,6 +204,10 @@ impl Lovely {
         };
 
         let mut is_vanilla = false;
+        let mut strict = false;
+        let mut watch = false;
+        let mut use_cache = true;
+        let mut show_help = false;
 
         while let Some(opt) = opts.next_arg().expect("Failed to parse argument.") {
             match opt {
@@ -142,7 +215,14 @@ impl Lovely {
                     mod_dir = opts.value().map(PathBuf::from).unwrap_or(mod_dir)
                 }
                 Arg::Long("vanilla") => is_vanilla = true,
-                _ => (),
+                Arg::Long("lovely-strict") => strict = true,
+                Arg::Long("lovely-watch") => watch = true,
+                Arg::Long("lovely-no-cache") => use_cache = false,
+                Arg::Long("help") => show_help = true,
+                // Arguments of mods are parsed with the patch table. Discard their values here.
+                _ => {
+                    let _ = opts.value_opt();
+                }
             }
         }
 
@@ -152,7 +232,7 @@ impl Lovely {
 
         info!("Lovely {LOVELY_VERSION}");
 
-        let lua_vars = Arc::new(RwLock::new(HashMap::new()));
+        let lua_vars: Arc<RwLock<RuntimeVars>> = Default::default();
 
         // Stop here if we're running in vanilla mode.
         if is_vanilla {
@@ -161,10 +241,14 @@ impl Lovely {
             let lovely = Lovely {
                 mod_dir,
                 is_vanilla,
+                strict,
                 loadbuffer,
                 patch_table: Default::default(),
                 dump_all,
                 lua_vars,
+                report: Default::default(),
+                reload_event: Default::default(),
+                cache: None,
             };
             RUNTIME
                 .set(lovely)
@@ -197,7 +281,25 @@ impl Lovely {
         }
 
         info!("Using mod directory at {mod_dir:?}");
-        let patch_table = Arc::new(RwLock::new(PatchTable::load(&mod_dir).unwrap()));
+        if strict {
+            info!("Running in strict mode, unmatched patches are errors");
+        }
+        if watch {
+            info!("Watching the mod directory for changes to patches");
+        }
+
+        let mut patch_table = PatchTable::load_with_args(&mod_dir, &args).unwrap();
+        patch_table.strict = strict;
+        patch_table.runtime_vars = Arc::clone(&lua_vars);
+
+        if show_help {
+            let help = format!("{LOVELY_HELP}{}", patch_table.args.help());
+            println!("{help}");
+            info!("{help}");
+            process::exit(0);
+        }
+        let report = PatchReport::new(&patch_table);
+        let patch_table = Arc::new(RwLock::new(patch_table));
 
         // Clean up dump dirs
         for dir_name in ["dump", "game-dump"] {
@@ -212,6 +314,12 @@ impl Lovely {
             });
         }
 
+        let cache = use_cache.then(|| PatchCache::new(&mod_dir));
+        match &cache {
+            Some(cache) => cache.prune(cache::CACHE_MAX_AGE),
+            None => info!("Patch cache is disabled"),
+        }
+
         info!(
             "Initialization complete in {}ms",
             start.elapsed().as_millis()
@@ -220,15 +328,73 @@ impl Lovely {
         let lovely = Lovely {
             mod_dir,
             is_vanilla,
+            strict,
             loadbuffer,
             patch_table,
             dump_all,
             lua_vars,
+            report: Arc::new(RwLock::new(report)),
+            reload_event: Default::default(),
+            cache,
         };
+        lovely.report.read().unwrap().write(&lovely.report_path());
+        lovely
+            .patch_table
+            .read()
+            .unwrap()
+            .write_load_order(&lovely.load_order_path());
+
         RUNTIME
             .set(lovely)
             .unwrap_or_else(|_| panic!("Shit's erroring"));
-        RUNTIME.get().unwrap()
+        let lovely = RUNTIME.get().unwrap();
+
+        report::spawn_writer(lovely, report::FLUSH_INTERVAL);
+        if watch {
+            watch::spawn(lovely, watch::WATCH_INTERVAL);
+        }
+
+        lovely
+    }
+
+    /// Reload the patch table from the mod directory and reset the patch report.
+    pub fn reload_patches(&self) -> anyhow::Result<()> {
+        let args = env::args().skip(1).collect_vec();
+        let mut new_table = PatchTable::load_with_args(&self.mod_dir, &args)?;
+        new_table.strict = self.strict;
+        new_table.runtime_vars = Arc::clone(&self.lua_vars);
+        let new_report = PatchReport::new(&new_table);
+        new_table.write_load_order(&self.load_order_path());
+
+        // Write the new report through its lock, so that it isn't written concurrently with the
+        // report writer thread.
+        let mut patch_table = write_when_free(&self.patch_table);
+        let mut report = self.report.write().unwrap();
+        *report = new_report;
+        *patch_table = new_table;
+        drop(patch_table);
+        report.write(&self.report_path());
+        Ok(())
+    }
+
+    /// The path of the patch report, MOD_DIR/lovely/report.json.
+    pub fn report_path(&self) -> PathBuf {
+        self.mod_dir.join("lovely").join("report.json")
+    }
+
+    /// The path of the resolved patch order, MOD_DIR/lovely/log/load-order.txt.
+    pub fn load_order_path(&self) -> PathBuf {
+        self.mod_dir.join("lovely").join("log").join("load-order.txt")
+    }
+
+    /// Record patch outcomes for the target into the patch report. The report is written to
+    /// disk by the thread spawned with [`report::spawn_writer`].
+    fn record_results(&self, target: &str, results: &[PatchResult]) {
+        if results.is_empty() {
+            return;
+        }
+
+        self.report.write().unwrap().record(target, results);
     }
 
     /// Apply patches onto the raw buffer.
@@ -261,19 +427,28 @@ impl Lovely {
                 let module_patches: Vec<_> = patch_table
                     .patches
                     .iter()
-                    .filter_map(|(x, prio, path)| match x {
-                        Patch::Module(patch) => Some((patch, prio, path)),
+                    .enumerate()
+                    .filter_map(|(i, x)| match &x.patch {
+                        Patch::Module(patch) => Some((i, patch, x.priority, &x.path)),
                         _ => None,
                     })
-                    .filter(|(x, _, _)| !x.load_now)
-                    .sorted_by_key(|(_, &prio, _)| prio)
-                    .map(|(x, _, path)| (x, path))
+                    .filter(|(_, x, _, _)| !x.load_now)
+                    .sorted_by_key(|(_, _, prio, _)| *prio)
+                    .map(|(i, x, _, path)| (i, x, path))
                     .collect();
 
-                for (patch, path) in module_patches {
+                let mut results = Vec::new();
+                for (patch_index, patch, path) in module_patches {
                     let patch: &ModulePatch = patch;
-                    let _ = unsafe { patch.apply("", state, path) };
+                    if let Ok(true) = unsafe { patch.apply("", state, path) } {
+                        results.push(PatchResult {
+                            patch_index,
+                            matches: None,
+                            errors: Vec::new(),
+                        });
+                    }
                 }
+                self.record_results("", &results);
             }
         }
         let name = match CStr::from_ptr(name_ptr as _).to_str() {
@@ -309,19 +484,72 @@ impl Lovely {
             name.replace("@", "")
         };
 
-        // Apply patches onto this buffer.
-        let res = patch_table.apply_patches(name, buf_str, state);
-        if res.is_err() {
-            state.push(res.unwrap_err());
-            // NOTE: Not really a great error but it doesn't handle the correcter errors right.
-            return 3; // LUA_ERRSYNTAX
+        // Evaluate load_now modules, then apply patches onto this buffer.
+        match patch_table.apply_module_patches(name, state) {
+            Ok(results) => self.record_results(name, &results),
+            Err(e) => {
+                state.push(e);
+                // NOTE: Not really a great error but it doesn't handle the correcter errors right.
+                return LUA_ERRSYNTAX;
+            }
         }
-        let (patched, debug) = res.unwrap();
+
+        // Serve the output of a previous launch if neither the buffer nor its patches changed.
+        let cached = self.cache.as_ref().and_then(|cache| {
+            let key = PatchCache::key(&patch_table, name, buf_str)?;
+            Some((cache, key))
+        });
+        let hit = cached
+            .as_ref()
+            .and_then(|(cache, key)| cache.get(&patch_table, name, key));
+        let from_cache = hit.is_some();
+
+        let (patched, debug) = match hit {
+            Some(x) => x,
+            None => match patch_table.apply_patches(name, buf_str) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("{e}");
+                    state.push(e);
+                    return LUA_ERRSYNTAX;
+                }
+            },
+        };
+        self.record_results(name, &debug.results);
 
         write_dump(&self.mod_dir, "game-dump", &pretty_name, &patched, &PatchDebug::new(name));
         write_dump(&self.mod_dir, "dump", &pretty_name, &patched, &debug);
 
-        (self.loadbuffer)(state, patched.as_ptr(), patched.len(), name_ptr, mode_ptr)
+        // Lua has the final say on whether the patched buffer is valid. Its syntax errors are
+        // only annotated with the patch responsible for the offending line.
+        let status = (self.loadbuffer)(state, patched.as_ptr(), patched.len(), name_ptr, mode_ptr);
+        if status == LUA_ERRSYNTAX {
+            let message = state.to_string(-1);
+            if let Some(explanation) = explain_syntax_error(&message, &debug) {
+                let message = format!("{message}\n{explanation}");
+                error!("Patched target '{name}' failed to load: {message}");
+                sys::lua_settop(state, -2);
+                state.push(message);
+            }
+        } else if status == 0 && !from_cache {
+            if let Some((cache, key)) = &cached {
+                cache.put(&patch_table, name, key, &patched, &debug);
+            }
+        }
+
+        status
+    }
+}
+
+/// Acquire a write lock without queueing behind readers. A queued writer blocks new readers,
+/// which deadlocks the Lua thread as it re-enters the read lock while loading module patches.
+fn write_when_free<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    loop {
+        match lock.try_write() {
+            Ok(guard) => return guard,
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            Err(TryLockError::Poisoned(e)) => panic!("Patch table lock was poisoned: {e}"),
+        }
     }
 }
 
@@ -333,16 +561,28 @@ unsafe extern "C" fn apply_patches(lua_state: *mut LuaState) -> c_int {
     let buf = check_lua_string(lua_state, 2);
     let mut num = 1;
     let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
-        let binding = RUNTIME.get().unwrap().patch_table.read().unwrap();
+        let lovely = RUNTIME.get().unwrap();
+        let binding = lovely.patch_table.read().unwrap();
         if binding.needs_patching(&buf_name) {
-            let res = binding.apply_patches(&buf_name, &buf, lua_state);
-            if res.is_err() {
-                lua_state.push(false);
-                lua_state.push(res.unwrap_err());
-                num = 2;
-                return;
+            match binding.apply_module_patches(&buf_name, lua_state) {
+                Ok(results) => lovely.record_results(&buf_name, &results),
+                Err(e) => {
+                    lua_state.push(false);
+                    lua_state.push(e);
+                    num = 2;
+                    return;
+                }
             }
-            let (patched, _debug) = res.unwrap();
+            let (patched, debug) = match binding.apply_patches(&buf_name, &buf) {
+                Ok(x) => x,
+                Err(e) => {
+                    lua_state.push(false);
+                    lua_state.push(e);
+                    num = 2;
+                    return;
+                }
+            };
+            lovely.record_results(&buf_name, &debug.results);
             lua_state.push(patched);
         } else {
             lua_state.push(buf)
@@ -358,23 +598,49 @@ unsafe extern "C" fn apply_patches(lua_state: *mut LuaState) -> c_int {
 }
 
 impl Target {
+    /// Whether this target can match more than one name, through globs or a regex.
+    pub fn is_dynamic(&self) -> bool {
+        match self {
+            Self::Single(name) => name.is_glob(),
+            Self::Multi(names) => names.iter().any(TargetName::is_glob),
+            Self::Regex { .. } => true,
+        }
+    }
+
     pub fn can_apply(&self, target: &str) -> bool {
         match self {
-            Self::Single(str) => str == target,
-            Self::Multi(strs) => strs.iter().any(|x| x == target),
+            Self::Single(name) => name.matches(target),
+            Self::Multi(names) => names.iter().any(|x| x.matches(target)),
+            Self::Regex { regex } => regex.is_match(target),
         }
     }
 
-    pub fn insert_into(&self, targets: &mut HashSet<String>) {
+    pub fn insert_into(&self, targets: &mut TargetSet) {
         match self {
-            Self::Single(str) => {
-                targets.insert(str.clone());
-            }
-            Self::Multi(strs) => {
-                for target in strs.iter() {
-                    targets.insert(target.clone());
+            Self::Single(name) => targets.insert(name.as_str()),
+            Self::Multi(names) => {
+                for name in names.iter() {
+                    targets.insert(name.as_str());
                 }
             }
+            Self::Regex { regex } => targets.insert_regex(regex),
+        }
+    }
+
+    /// Split into one target per name, for display and reporting.
+    pub fn split(&self) -> Vec<Target> {
+        match self {
+            Self::Multi(names) => names.iter().cloned().map(Self::Single).collect(),
+            _ => vec![self.clone()],
+        }
+    }
+
+    /// The name, glob or regex of each target, for display.
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            Self::Single(name) => vec![name.as_str().to_string()],
+            Self::Multi(names) => names.iter().map(|x| x.as_str().to_string()).collect(),
+            Self::Regex { regex } => vec![regex.as_str().to_string()],
         }
     }
 }
@@ -0,0 +1,903 @@
+//! A recursive descent parser for Lua 5.1 with the LuaJIT extensions (`goto`, labels).
+//!
+//! It does not build a full syntax tree. Parsing validates the source and records the location
+//! of every named function definition, which is what structural patches need.
+
+use std::fmt;
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// Longest first, so that the first prefix match is the right one.
+const SYMBOLS: &[&str] = &[
+    "...", "..", "==", "~=", "<=", ">=", "::", "+", "-", "*", "/", "%", "^", "#", "<", ">", "=",
+    "(", ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+// Priority of unary operators, see lparser.c.
+const UNARY_PRIORITY: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    // Byte offset of the offending token.
+    pub offset: usize,
+    // 1-based line of the offending token.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// A named function definition, either a `function` statement, a function assigned to a name
+/// or field, or a function in a table constructor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionDef {
+    // Qualified name as written, e.g. `Card:calculate_joker`, `G.FUNCS.play_cards` or `helper`.
+    // Functions in table constructors are named after the table, e.g. `M.helper` for
+    // `M = { helper = function() end }`.
+    pub name: String,
+    // Byte range of the whole definition, up to and including the closing `end`.
+    pub start: usize,
+    pub end: usize,
+    // Byte range of the body, between the parameter list and the closing `end`.
+    pub body_start: usize,
+    pub body_end: usize,
+    pub is_vararg: bool,
+}
+
+impl FunctionDef {
+    /// Check if this definition has the provided name. `:` and `.` are interchangeable, so
+    /// `Card.calculate_joker` also finds `function Card:calculate_joker()`.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.name.replace(':', ".") == name.replace(':', ".")
+    }
+}
+
+/// Parse the Lua source, returning every named function definition in source order.
+pub fn parse(source: &str) -> Result<Vec<FunctionDef>, SyntaxError> {
+    let tokens = Lexer::new(source).tokenize()?;
+    let mut parser = Parser {
+        source,
+        tokens,
+        pos: 0,
+        vararg: vec![true],
+        functions: Vec::new(),
+    };
+
+    parser.block()?;
+    if parser.peek().kind != TokenKind::Eof {
+        return Err(parser.error_near("'<eof>' expected"));
+    }
+
+    let mut functions = parser.functions;
+    functions.sort_by_key(|x| x.start);
+    Ok(functions)
+}
+
+/// The 1-based line of the byte offset.
+pub fn line_of(source: &str, offset: usize) -> usize {
+    source.as_bytes()[..offset.min(source.len())]
+        .iter()
+        .filter(|x| **x == b'\n')
+        .count()
+        + 1
+}
+
+fn error_at(source: &str, offset: usize, message: String) -> SyntaxError {
+    SyntaxError {
+        offset,
+        line: line_of(source, offset),
+        message,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    Name,
+    Keyword,
+    Symbol,
+    String,
+    Number,
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Lexer {
+            source,
+            bytes: source.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.bytes.get(self.pos + n).copied()
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Token>, SyntaxError> {
+        // Skip a shebang line, like luaL_loadfile does.
+        if self.bytes.starts_with(b"#") {
+            self.skip_line();
+        }
+
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next_token()?;
+            tokens.push(token);
+            if token.kind == TokenKind::Eof {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while self.peek(0).is_some_and(|x| x != b'\n') {
+            self.pos += 1;
+        }
+    }
+
+    /// The level of the long bracket (`[[`, `[==[`) starting at the current position, if any.
+    fn long_bracket_level(&self) -> Option<usize> {
+        let level = self.bytes[self.pos + 1..]
+            .iter()
+            .take_while(|x| **x == b'=')
+            .count();
+        (self.peek(level + 1) == Some(b'[')).then_some(level)
+    }
+
+    fn skip_long_bracket(&mut self, level: usize, what: &str) -> Result<(), SyntaxError> {
+        let start = self.pos;
+        let close = format!("]{}]", "=".repeat(level));
+
+        self.pos += level + 2;
+        match self.source[self.pos..].find(&close) {
+            Some(i) => {
+                self.pos += i + close.len();
+                Ok(())
+            }
+            None => Err(error_at(self.source, start, format!("unfinished long {what}"))),
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, SyntaxError> {
+        // Skip whitespace and comments.
+        loop {
+            match self.peek(0) {
+                Some(b' ' | b'\t' | b'\r' | b'\n' | b'\x0b' | b'\x0c') => self.pos += 1,
+                Some(b'-') if self.peek(1) == Some(b'-') => {
+                    self.pos += 2;
+                    match self.peek(0) {
+                        Some(b'[') => match self.long_bracket_level() {
+                            Some(level) => self.skip_long_bracket(level, "comment")?,
+                            None => self.skip_line(),
+                        },
+                        _ => self.skip_line(),
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        let start = self.pos;
+        let Some(c) = self.peek(0) else {
+            return Ok(Token {
+                kind: TokenKind::Eof,
+                start,
+                end: start,
+            });
+        };
+
+        let kind = match c {
+            b'"' | b'\'' => {
+                self.pos += 1;
+                loop {
+                    match self.peek(0) {
+                        None | Some(b'\n') => {
+                            return Err(error_at(self.source, start, "unfinished string".into()));
+                        }
+                        Some(b'\\') => {
+                            self.pos += 1;
+                            match self.peek(0) {
+                                // An escaped line break continues the string on the next line.
+                                // `\r\n` and `\n\r` are a single line break.
+                                Some(x @ (b'\r' | b'\n')) => {
+                                    self.pos += 1;
+                                    let next = self.peek(0);
+                                    if next != Some(x) && matches!(next, Some(b'\r' | b'\n')) {
+                                        self.pos += 1;
+                                    }
+                                }
+                                // `\z` skips the whitespace after it, including line breaks.
+                                Some(b'z') => {
+                                    self.pos += 1;
+                                    while matches!(
+                                        self.peek(0),
+                                        Some(b' ' | b'\t' | b'\r' | b'\n' | b'\x0b' | b'\x0c')
+                                    ) {
+                                        self.pos += 1;
+                                    }
+                                }
+                                Some(_) => self.pos += 1,
+                                None => (),
+                            }
+                        }
+                        Some(x) if x == c => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(_) => self.pos += 1,
+                    }
+                }
+                TokenKind::String
+            }
+            b'[' if self.long_bracket_level().is_some() => {
+                let level = self.long_bracket_level().unwrap();
+                self.skip_long_bracket(level, "string")?;
+                TokenKind::String
+            }
+            b'0'..=b'9' => self.number(),
+            b'.' if self.peek(1).is_some_and(|x| x.is_ascii_digit()) => self.number(),
+            // LuaJIT accepts any non-ASCII byte in identifiers.
+            c if c.is_ascii_alphabetic() || c == b'_' || c >= 0x80 => {
+                while self
+                    .peek(0)
+                    .is_some_and(|x| x.is_ascii_alphanumeric() || x == b'_' || x >= 0x80)
+                {
+                    self.pos += 1;
+                }
+
+                if KEYWORDS.contains(&&self.source[start..self.pos]) {
+                    TokenKind::Keyword
+                } else {
+                    TokenKind::Name
+                }
+            }
+            _ => {
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|x| self.bytes[start..].starts_with(x.as_bytes()));
+                let Some(symbol) = symbol else {
+                    return Err(error_at(
+                        self.source,
+                        start,
+                        format!("unexpected symbol near '{}'", c as char),
+                    ));
+                };
+
+                self.pos += symbol.len();
+                TokenKind::Symbol
+            }
+        };
+
+        Ok(Token {
+            kind,
+            start,
+            end: self.pos.min(self.bytes.len()),
+        })
+    }
+
+    fn number(&mut self) -> TokenKind {
+        let hex = self.peek(0) == Some(b'0') && matches!(self.peek(1), Some(b'x' | b'X'));
+
+        while let Some(x) = self.peek(0) {
+            if !(x.is_ascii_alphanumeric() || x == b'.' || x == b'_') {
+                break;
+            }
+
+            self.pos += 1;
+            let exponent = if hex {
+                matches!(x, b'p' | b'P')
+            } else {
+                matches!(x, b'e' | b'E')
+            };
+            if exponent && matches!(self.peek(0), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+        }
+
+        TokenKind::Number
+    }
+}
+
+// Whether a suffixed expression can be assigned to, or stands on its own as a call statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExprKind {
+    Assignable,
+    Call,
+    Other,
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    // Whether each enclosing function accepts `...`, innermost last.
+    vararg: Vec<bool>,
+    functions: Vec<FunctionDef>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Token {
+        self.tokens[self.pos]
+    }
+
+    fn text(&self, token: Token) -> &'a str {
+        &self.source[token.start..token.end]
+    }
+
+    fn line(&self) -> usize {
+        line_of(self.source, self.peek().start)
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.peek();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    /// Check if the next token is the provided keyword or symbol.
+    fn check(&self, text: &str) -> bool {
+        let token = self.peek();
+        matches!(token.kind, TokenKind::Keyword | TokenKind::Symbol) && self.text(token) == text
+    }
+
+    fn accept(&mut self, text: &str) -> bool {
+        let found = self.check(text);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn error_near(&self, message: &str) -> SyntaxError {
+        let token = self.peek();
+        let near = match token.kind {
+            TokenKind::Eof => "<eof>".to_string(),
+            _ => format!("'{}'", self.text(token)),
+        };
+
+        error_at(self.source, token.start, format!("{message} near {near}"))
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, SyntaxError> {
+        if self.check(text) {
+            Ok(self.advance())
+        } else {
+            Err(self.error_near(&format!("'{text}' expected")))
+        }
+    }
+
+    /// Expect the token closing a construct opened at `line`, mentioning the opener if it is on
+    /// another line, like Lua does.
+    fn expect_match(&mut self, text: &str, opener: &str, line: usize) -> Result<Token, SyntaxError> {
+        if self.check(text) {
+            Ok(self.advance())
+        } else if line == self.line() {
+            Err(self.error_near(&format!("'{text}' expected")))
+        } else {
+            Err(self.error_near(&format!(
+                "'{text}' expected (to close '{opener}' at line {line})"
+            )))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<&'a str, SyntaxError> {
+        if self.peek().kind == TokenKind::Name {
+            let token = self.advance();
+            Ok(self.text(token))
+        } else {
+            Err(self.error_near("<name> expected"))
+        }
+    }
+
+    /// The contents of a quoted string token without escapes, used for `t["key"]` names.
+    fn string_value(&self, token: Token) -> Option<&'a str> {
+        let text = self.text(token);
+        if token.kind != TokenKind::String || text.starts_with('[') || text.contains('\\') {
+            return None;
+        }
+        Some(&text[1..text.len() - 1])
+    }
+
+    fn block_follow(&self) -> bool {
+        self.peek().kind == TokenKind::Eof
+            || ["else", "elseif", "end", "until"]
+                .iter()
+                .any(|x| self.check(x))
+    }
+
+    fn block(&mut self) -> Result<(), SyntaxError> {
+        while !self.block_follow() {
+            // `return` must be the last statement of a block.
+            if self.accept("return") {
+                if !self.block_follow() && !self.check(";") {
+                    // Tables returned from a chunk name their functions after their own fields.
+                    self.expr_list(&[Some(String::new())])?;
+                }
+                self.accept(";");
+                return Ok(());
+            }
+
+            self.statement()?;
+        }
+
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), SyntaxError> {
+        let token = self.peek();
+        let line = self.line();
+        let keyword = match token.kind {
+            TokenKind::Keyword | TokenKind::Symbol => self.text(token),
+            _ => "",
+        };
+
+        match keyword {
+            ";" | "break" => {
+                self.advance();
+            }
+            "::" => {
+                self.advance();
+                self.expect_name()?;
+                self.expect("::")?;
+            }
+            "goto" => {
+                self.advance();
+                self.expect_name()?;
+            }
+            "do" => {
+                self.advance();
+                self.block()?;
+                self.expect_match("end", "do", line)?;
+            }
+            "while" => {
+                self.advance();
+                self.expr(None)?;
+                self.expect("do")?;
+                self.block()?;
+                self.expect_match("end", "while", line)?;
+            }
+            "repeat" => {
+                self.advance();
+                self.block()?;
+                self.expect_match("until", "repeat", line)?;
+                self.expr(None)?;
+            }
+            "if" => {
+                self.advance();
+                self.expr(None)?;
+                self.expect("then")?;
+                self.block()?;
+                while self.accept("elseif") {
+                    self.expr(None)?;
+                    self.expect("then")?;
+                    self.block()?;
+                }
+                if self.accept("else") {
+                    self.block()?;
+                }
+                self.expect_match("end", "if", line)?;
+            }
+            "for" => {
+                self.advance();
+                self.expect_name()?;
+                if self.accept("=") {
+                    self.expr(None)?;
+                    self.expect(",")?;
+                    self.expr(None)?;
+                    if self.accept(",") {
+                        self.expr(None)?;
+                    }
+                } else if self.check(",") || self.check("in") {
+                    while self.accept(",") {
+                        self.expect_name()?;
+                    }
+                    self.expect("in")?;
+                    self.expr_list(&[])?;
+                } else {
+                    return Err(self.error_near("'=' or 'in' expected"));
+                }
+                self.expect("do")?;
+                self.block()?;
+                self.expect_match("end", "for", line)?;
+            }
+            "function" => {
+                self.advance();
+                let mut name = self.expect_name()?.to_string();
+                while self.accept(".") {
+                    name = format!("{name}.{}", self.expect_name()?);
+                }
+                if self.accept(":") {
+                    name = format!("{name}:{}", self.expect_name()?);
+                }
+                self.func_body(token.start, Some(name), line)?;
+            }
+            "local" => {
+                self.advance();
+                if self.accept("function") {
+                    let name = self.expect_name()?.to_string();
+                    self.func_body(token.start, Some(name), line)?;
+                } else {
+                    let mut names = vec![Some(self.expect_name()?.to_string())];
+                    while self.accept(",") {
+                        names.push(Some(self.expect_name()?.to_string()));
+                    }
+                    if self.accept("=") {
+                        self.expr_list(&names)?;
+                    }
+                }
+            }
+            _ => self.expr_statement()?,
+        }
+
+        Ok(())
+    }
+
+    fn expr_statement(&mut self) -> Result<(), SyntaxError> {
+        let (name, kind) = self.suffixed_expr()?;
+        if !self.check("=") && !self.check(",") {
+            if kind != ExprKind::Call {
+                return Err(self.error_near("syntax error"));
+            }
+            return Ok(());
+        }
+
+        let mut names = vec![name];
+        let mut kinds = vec![kind];
+        while self.accept(",") {
+            let (name, kind) = self.suffixed_expr()?;
+            names.push(name);
+            kinds.push(kind);
+        }
+        if kinds.iter().any(|x| *x != ExprKind::Assignable) {
+            return Err(self.error_near("syntax error"));
+        }
+
+        self.expect("=")?;
+        self.expr_list(&names)
+    }
+
+    /// Parse a function's parameters and body, recording it if it has a name.
+    fn func_body(&mut self, start: usize, name: Option<String>, line: usize) -> Result<(), SyntaxError> {
+        self.expect("(")?;
+        let mut is_vararg = false;
+        if !self.check(")") {
+            loop {
+                if self.accept("...") {
+                    is_vararg = true;
+                    break;
+                }
+                self.expect_name()?;
+                if !self.accept(",") {
+                    break;
+                }
+            }
+        }
+        let close = self.expect(")")?;
+
+        self.vararg.push(is_vararg);
+        self.block()?;
+        self.vararg.pop();
+        let end = self.expect_match("end", "function", line)?;
+
+        if let Some(name) = name {
+            self.functions.push(FunctionDef {
+                name,
+                start,
+                end: end.end,
+                body_start: close.end,
+                body_end: end.start,
+                is_vararg,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Parse a name or parenthesized expression followed by any number of field accesses,
+    /// indexes and calls. Returns the dotted name of the expression if it is a plain field
+    /// path like `G.FUNCS.play_cards`.
+    fn suffixed_expr(&mut self) -> Result<(Option<String>, ExprKind), SyntaxError> {
+        let line = self.line();
+        let (mut name, mut kind) = if self.peek().kind == TokenKind::Name {
+            (Some(self.expect_name()?.to_string()), ExprKind::Assignable)
+        } else if self.accept("(") {
+            self.expr(None)?;
+            self.expect_match(")", "(", line)?;
+            (None, ExprKind::Other)
+        } else {
+            return Err(self.error_near("unexpected symbol"));
+        };
+
+        loop {
+            if self.accept(".") {
+                let field = self.expect_name()?;
+                name = name.map(|x| format!("{x}.{field}"));
+                kind = ExprKind::Assignable;
+            } else if self.accept("[") {
+                let key = self.index_key()?;
+                self.expect("]")?;
+                name = name.zip(key).map(|(x, key)| format!("{x}.{key}"));
+                kind = ExprKind::Assignable;
+            } else if self.accept(":") {
+                self.expect_name()?;
+                self.call_args()?;
+                name = None;
+                kind = ExprKind::Call;
+            } else if self.check("(") || self.check("{") || self.peek().kind == TokenKind::String {
+                self.call_args()?;
+                name = None;
+                kind = ExprKind::Call;
+            } else {
+                return Ok((name, kind));
+            }
+        }
+    }
+
+    /// Parse the expression of an index, returning it if it is a plain string.
+    fn index_key(&mut self) -> Result<Option<&'a str>, SyntaxError> {
+        let start = self.pos;
+        self.expr(None)?;
+        if self.pos == start + 1 {
+            Ok(self.string_value(self.tokens[start]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn call_args(&mut self) -> Result<(), SyntaxError> {
+        let line = self.line();
+        if self.accept("(") {
+            if !self.check(")") {
+                self.expr_list(&[])?;
+            }
+            self.expect_match(")", "(", line)?;
+        } else if self.check("{") {
+            self.constructor(None)?;
+        } else if self.peek().kind == TokenKind::String {
+            self.advance();
+        } else {
+            return Err(self.error_near("function arguments expected"));
+        }
+
+        Ok(())
+    }
+
+    /// Parse a comma separated list of expressions. Each expression is named by the matching
+    /// entry of `names`, which holds the targets of an assignment.
+    fn expr_list(&mut self, names: &[Option<String>]) -> Result<(), SyntaxError> {
+        let mut i = 0;
+        loop {
+            self.expr(names.get(i).and_then(|x| x.as_deref()))?;
+            i += 1;
+            if !self.accept(",") {
+                return Ok(());
+            }
+        }
+    }
+
+    fn expr(&mut self, name: Option<&str>) -> Result<(), SyntaxError> {
+        self.sub_expr(name, 0)
+    }
+
+    /// Left and right priority of the binary operator at the current token, see lparser.c.
+    fn binary_priority(&self) -> Option<(u8, u8)> {
+        let token = self.peek();
+        if !matches!(token.kind, TokenKind::Keyword | TokenKind::Symbol) {
+            return None;
+        }
+
+        match self.text(token) {
+            "+" | "-" => Some((6, 6)),
+            "*" | "/" | "%" => Some((7, 7)),
+            "^" => Some((10, 9)),
+            ".." => Some((5, 4)),
+            "==" | "~=" | "<" | "<=" | ">" | ">=" => Some((3, 3)),
+            "and" => Some((2, 2)),
+            "or" => Some((1, 1)),
+            _ => None,
+        }
+    }
+
+    fn sub_expr(&mut self, name: Option<&str>, limit: u8) -> Result<(), SyntaxError> {
+        if self.check("not") || self.check("-") || self.check("#") {
+            self.advance();
+            self.sub_expr(None, UNARY_PRIORITY)?;
+        } else {
+            self.simple_expr(name)?;
+        }
+
+        while let Some((left, right)) = self.binary_priority() {
+            if left <= limit {
+                break;
+            }
+            self.advance();
+            self.sub_expr(None, right)?;
+        }
+
+        Ok(())
+    }
+
+    fn simple_expr(&mut self, name: Option<&str>) -> Result<(), SyntaxError> {
+        let token = self.peek();
+        match token.kind {
+            TokenKind::Number | TokenKind::String => {
+                self.advance();
+                return Ok(());
+            }
+            TokenKind::Name | TokenKind::Eof => {
+                self.suffixed_expr()?;
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        match self.text(token) {
+            "nil" | "true" | "false" => {
+                self.advance();
+            }
+            "..." => {
+                if !self.vararg.last().copied().unwrap_or(true) {
+                    return Err(self.error_near("cannot use '...' outside a vararg function"));
+                }
+                self.advance();
+            }
+            "{" => self.constructor(name)?,
+            "function" => {
+                let line = self.line();
+                self.advance();
+                self.func_body(token.start, name.map(String::from), line)?;
+            }
+            _ => {
+                self.suffixed_expr()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse a table constructor. Functions in its fields are named after `prefix`, the name of
+    /// the table itself.
+    fn constructor(&mut self, prefix: Option<&str>) -> Result<(), SyntaxError> {
+        let line = self.line();
+        let field_name = |key: &str| {
+            prefix.map(|x| match x {
+                "" => key.to_string(),
+                x => format!("{x}.{key}"),
+            })
+        };
+
+        self.expect("{")?;
+        while !self.check("}") {
+            if self.accept("[") {
+                let key = self.index_key()?;
+                self.expect("]")?;
+                self.expect("=")?;
+                self.expr(key.and_then(field_name).as_deref())?;
+            } else if self.peek().kind == TokenKind::Name
+                && self.tokens.get(self.pos + 1).is_some_and(|x| {
+                    x.kind == TokenKind::Symbol && self.text(*x) == "="
+                })
+            {
+                let key = self.expect_name()?;
+                self.advance();
+                self.expr(field_name(key).as_deref())?;
+            } else {
+                self.expr(None)?;
+            }
+
+            if !self.accept(",") && !self.accept(";") {
+                break;
+            }
+        }
+        self.expect_match("}", "{", line)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(source: &str) -> Vec<String> {
+        parse(source).unwrap().into_iter().map(|x| x.name).collect()
+    }
+
+    #[test]
+    fn finds_named_functions() {
+        let source = r#"
+function Card:calculate_joker(context)
+    local function helper(x) return x end
+    return helper(context)
+end
+
+G.FUNCS.play_cards = function(e) end
+local M = { update = function(dt) end, ["draw"] = function() end, nested = { f = function() end } }
+local anonymous = call(function() end)
+return { exported = function() end }
+"#;
+
+        assert_eq!(
+            names(source),
+            vec![
+                "Card:calculate_joker",
+                "helper",
+                "G.FUNCS.play_cards",
+                "M.update",
+                "M.draw",
+                "M.nested.f",
+                "exported",
+            ]
+        );
+    }
+
+    #[test]
+    fn function_ranges_cover_the_body() {
+        let source = "function f(a, ...)\n  return a\nend\n";
+        let def = &parse(source).unwrap()[0];
+
+        assert_eq!(&source[def.start..def.end], "function f(a, ...)\n  return a\nend");
+        assert_eq!(&source[def.body_start..def.body_end], "\n  return a\n");
+        assert!(def.is_vararg);
+        assert!(def.has_name("f"));
+    }
+
+    #[test]
+    fn skips_strings_and_comments() {
+        let source = r#"
+local s = "function fake() end" .. 'end' .. [==[ end ]] ]==]
+--[[ function commented() end ]]
+-- function also_commented() end
+function real() goto done ::done:: end
+"#;
+
+        assert_eq!(names(source), vec!["real"]);
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_lines() {
+        let err = parse("function f()\n  if x then\n    y()\nend\n").unwrap_err();
+        assert_eq!(err.line, 5);
+        assert_eq!(err.message, "'end' expected (to close 'function' at line 1) near <eof>");
+
+        let err = parse("local x = 1\nx\n").unwrap_err();
+        assert_eq!(err.line, 3);
+        assert_eq!(err.message, "syntax error near <eof>");
+
+        let err = parse("function f()\n  return ...\nend").unwrap_err();
+        assert_eq!(err.line, 2);
+
+        assert!(parse("local s = 'unfinished\n").is_err());
+    }
+
+    #[test]
+    fn escaped_line_breaks_continue_strings() {
+        assert!(parse("local s = 'a\\\r\nb'\r\nlocal t = 'c\\\n\rd'\n").is_ok());
+        assert!(parse("local s = 'a\\z\r\n    b'\nlocal t = \"c\\z\n\n\"\n").is_ok());
+        assert!(parse("local s = 'a\\\n\nb'\n").is_err());
+    }
+}
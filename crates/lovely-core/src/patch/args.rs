@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use getargs::{Arg, Options};
+use itertools::Itertools;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sys::{LuaState, Pushable};
+
+// Options parsed by Lovely itself, which mods can't declare. So are options starting with
+// `lovely-`.
+const LOVELY_OPTIONS: [&str; 5] = ["mod-dir", "vanilla", "dump-all", "disable-console", "help"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PatchArgs {
+    // An optional help string. This will be printed out in the calling console
+    // (if available) when the --help argument is supplied.
+    pub help: Option<String>,
+
+    // An optional default value. Not including a default value will cause Lovely
+    // to fail to start if this argument is missing or has no value.
+    // Consider this to be both a "default value" and a "required" field, depending
+    // on whether or not it's set. Flags default to false.
+    pub default: Option<String>,
+
+    // This field allows for a patch author to force lovely to parse incoming arguments
+    // with the exact name that they are defined by.
+    // This disables lovely's automatic underscore to hyphen conversion.
+    #[serde(default)]
+    pub name_override: bool,
+
+    // This field allows for arguments (--arg) to be passed without implicit values,
+    // treating it essentially as a flag. If it exists in the args, it's true, if not,
+    // then we set it to false.
+    #[serde(default)]
+    pub treat_as_flag: bool,
+}
+
+// An argument declared in the `[args]` table of a patch file.
+#[derive(Debug, Clone)]
+pub struct DeclaredArg {
+    // Id of the mod declaring the argument. Its value is exposed as a var of this mod.
+    pub mod_id: String,
+    // The key in `[args]`, which is also the name of the var.
+    pub key: String,
+    // Path of the declaring patch file, relative to the mod directory.
+    pub path: PathBuf,
+    pub spec: PatchArgs,
+}
+
+impl DeclaredArg {
+    /// The name of the argument on the command line, without the leading `--`.
+    pub fn name(&self) -> String {
+        if self.spec.name_override {
+            self.key.clone()
+        } else {
+            self.key.replace('_', "-")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgValue {
+    Flag(bool),
+    Value(String),
+}
+
+impl ArgValue {
+    /// The value as interpolated into patches. Flags are `true` or `false`.
+    pub fn as_var(&self) -> String {
+        match self {
+            ArgValue::Flag(x) => x.to_string(),
+            ArgValue::Value(x) => x.clone(),
+        }
+    }
+}
+
+impl Pushable for ArgValue {
+    unsafe fn push(&self, state: *mut LuaState) {
+        match self {
+            ArgValue::Flag(x) => x.push(state),
+            ArgValue::Value(x) => x.push(state),
+        }
+    }
+}
+
+/// The arguments declared by every loaded patch file, and their values parsed from the game's
+/// command line.
+#[derive(Debug, Default)]
+pub struct ArgTable {
+    declared: Vec<DeclaredArg>,
+    // Values by mod id, then by key.
+    values: BTreeMap<String, BTreeMap<String, ArgValue>>,
+    // Required arguments which were not passed, or passed without a value, by mod id.
+    errors: Vec<(String, String)>,
+}
+
+impl ArgTable {
+    /// Parse the declared arguments from the command line, excluding the executable.
+    /// Arguments which nothing declares are left to Lovely and the game. Arguments named like
+    /// one of Lovely's options or like an argument declared before them are ignored.
+    pub fn parse(declared: Vec<DeclaredArg>, args: &[String]) -> Self {
+        let declared = without_collisions(declared);
+        let mut passed: BTreeMap<String, Option<String>> = BTreeMap::new();
+        let names = declared.iter().map(|x| (x.name(), x.spec.treat_as_flag)).collect_vec();
+
+        let mut opts = Options::new(args.iter().map(String::as_str));
+        while let Ok(Some(opt)) = opts.next_arg() {
+            let Arg::Long(name) = opt else {
+                continue;
+            };
+            let value = match names.iter().find(|(x, _)| x == name) {
+                Some((_, true)) => opts.value_opt().map(String::from),
+                Some((_, false)) => opts.value().ok().map(String::from),
+                None => {
+                    // Skip values attached to options which aren't ours, e.g. `--other=x`.
+                    let _ = opts.value_opt();
+                    continue;
+                }
+            };
+            passed.insert(name.to_string(), value);
+        }
+
+        let mut table = ArgTable::default();
+        for arg in &declared {
+            let name = arg.name();
+            let value = match (passed.get(&name), &arg.spec.default) {
+                (Some(value), _) if arg.spec.treat_as_flag => ArgValue::Flag(
+                    value
+                        .as_deref()
+                        .is_none_or(|x| !matches!(x, "false" | "0" | "no" | "off")),
+                ),
+                (None, None) if arg.spec.treat_as_flag => ArgValue::Flag(false),
+                (Some(Some(value)), _) => ArgValue::Value(value.clone()),
+                (_, Some(default)) if arg.spec.treat_as_flag => ArgValue::Flag(default == "true"),
+                (_, Some(default)) => ArgValue::Value(default.clone()),
+                (Some(None), None) => {
+                    let error = format!(
+                        "Argument --{name} of patch file {} needs a value",
+                        arg.path.display()
+                    );
+                    table.errors.push((arg.mod_id.clone(), error));
+                    continue;
+                }
+                (None, None) => {
+                    let error = format!(
+                        "Argument --{name} of patch file {} is required",
+                        arg.path.display()
+                    );
+                    table.errors.push((arg.mod_id.clone(), error));
+                    continue;
+                }
+            };
+
+            table
+                .values
+                .entry(arg.mod_id.clone())
+                .or_default()
+                .insert(arg.key.clone(), value);
+        }
+
+        table.declared = declared;
+        table
+    }
+
+    /// Required arguments which are missing, as (mod id, error).
+    pub fn errors(&self) -> &[(String, String)] {
+        &self.errors
+    }
+
+    /// Every parsed value as (mod id, key, value).
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &ArgValue)> {
+        self.values.iter().flat_map(|(id, values)| {
+            values
+                .iter()
+                .map(move |(key, value)| (id.as_str(), key.as_str(), value))
+        })
+    }
+
+    /// The parsed values by mod id, then by key.
+    pub fn values(&self) -> &BTreeMap<String, BTreeMap<String, ArgValue>> {
+        &self.values
+    }
+
+    /// Help text listing every declared argument, grouped by mod.
+    pub fn help(&self) -> String {
+        let mut out = String::new();
+        for (mod_id, args) in &self.declared.iter().chunk_by(|x| &x.mod_id) {
+            out.push_str(&format!("\n{mod_id}:\n"));
+            for arg in args {
+                let usage = if arg.spec.treat_as_flag {
+                    format!("--{}", arg.name())
+                } else {
+                    format!("--{} <{}>", arg.name(), arg.key.to_uppercase())
+                };
+                let mut line = format!("  {usage:<32}");
+                if let Some(help) = &arg.spec.help {
+                    line.push_str(help);
+                }
+                match &arg.spec.default {
+                    Some(default) => line.push_str(&format!(" [default: {default}]")),
+                    None if !arg.spec.treat_as_flag => line.push_str(" [required]"),
+                    None => (),
+                }
+                out.push_str(line.trim_end());
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+/// Drop arguments named like one of Lovely's options, or like an argument declared before them.
+fn without_collisions(declared: Vec<DeclaredArg>) -> Vec<DeclaredArg> {
+    let mut kept: Vec<DeclaredArg> = Vec::new();
+    for arg in declared {
+        let name = arg.name();
+        if LOVELY_OPTIONS.contains(&name.as_str()) || name.starts_with("lovely-") {
+            warn!(
+                "Argument --{name} of patch file {} has the name of a Lovely option, ignoring it",
+                arg.path.display()
+            );
+        } else if let Some(first) = kept.iter().find(|x| x.name() == name) {
+            warn!(
+                "Argument --{name} of patch file {} is already declared by mod '{}' in {}, \
+                ignoring it",
+                arg.path.display(),
+                first.mod_id,
+                first.path.display()
+            );
+        } else {
+            kept.push(arg);
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arg(key: &str, default: Option<&str>, treat_as_flag: bool) -> DeclaredArg {
+        DeclaredArg {
+            mod_id: "my-mod".to_string(),
+            key: key.to_string(),
+            path: PathBuf::from("my-mod/lovely.toml"),
+            spec: PatchArgs {
+                help: Some(format!("Sets {key}")),
+                default: default.map(String::from),
+                name_override: false,
+                treat_as_flag,
+            },
+        }
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_values_flags_and_defaults() {
+        let declared = vec![
+            arg("debug_overlay", None, true),
+            arg("log_level", Some("info"), false),
+            arg("seed", None, false),
+            arg("fast", None, true),
+        ];
+        let table = ArgTable::parse(
+            declared,
+            &args("--mod-dir /mods --debug-overlay --unknown=1 --seed=ABC"),
+        );
+        assert!(table.errors().is_empty());
+
+        let values = &table.values()["my-mod"];
+        assert_eq!(values["debug_overlay"], ArgValue::Flag(true));
+        assert_eq!(values["log_level"], ArgValue::Value("info".to_string()));
+        assert_eq!(values["seed"], ArgValue::Value("ABC".to_string()));
+        assert_eq!(values["fast"], ArgValue::Flag(false));
+        assert!(table.help().contains("--seed <SEED>"));
+        assert!(table.help().contains("--log-level <LOG_LEVEL>         Sets log_level [default: info]"));
+    }
+
+    #[test]
+    fn missing_required_argument_is_an_error() {
+        let mut named = arg("Seed", None, false);
+        named.spec.name_override = true;
+        let table = ArgTable::parse(vec![named], &args("--seed 1"));
+
+        let (mod_id, err) = &table.errors()[0];
+        assert_eq!(mod_id, "my-mod");
+        assert!(err.contains("Argument --Seed of patch file my-mod/lovely.toml is required"));
+    }
+
+    #[test]
+    fn colliding_arguments_are_ignored() {
+        let mut other = arg("seed", Some("2"), false);
+        other.mod_id = "other-mod".to_string();
+        let declared = vec![
+            arg("mod_dir", None, false),
+            arg("disable_console", None, true),
+            arg("lovely_strict", None, true),
+            arg("seed", Some("1"), false),
+            other,
+        ];
+        let table = ArgTable::parse(declared, &args("--mod-dir /mods"));
+
+        assert!(table.errors().is_empty());
+        let values = table.iter().map(|(id, key, _)| (id, key)).collect_vec();
+        assert_eq!(values, vec![("my-mod", "seed")]);
+    }
+}
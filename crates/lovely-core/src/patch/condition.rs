@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use crate::LOVELY_VERSION;
+
+// The `if` block of a patch. Every condition which is set has to hold for the patch to be loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Condition {
+    // Mods which have to be loaded, by id, provided id or directory / zip name.
+    #[serde(default)]
+    pub mods: Vec<String>,
+    // Mods which must not be loaded.
+    #[serde(default)]
+    pub not_mods: Vec<String>,
+    // Vars which have to be set to these values. Vars of other mods are written as `modid.VAR`.
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    // Environment variables which have to be set, to any value.
+    #[serde(default)]
+    pub env: Vec<String>,
+    // Operating systems the patch applies on, as in `std::env::consts::OS`, e.g. "windows",
+    // "macos" or "linux".
+    #[serde(default)]
+    pub os: Vec<String>,
+    // The range of Lovely versions the patch applies on, such as ">=0.9.0".
+    #[serde(default)]
+    pub lovely_version: Option<VersionReq>,
+}
+
+impl Condition {
+    /// Check every condition against the loaded mods and the vars visible to the patch. Returns
+    /// the first one which doesn't hold.
+    pub fn check<'a>(
+        &self,
+        mods: &HashSet<String>,
+        var: impl Fn(&str) -> Option<&'a str>,
+    ) -> Result<(), String> {
+        if let Some(id) = self.mods.iter().find(|x| !mods.contains(*x)) {
+            return Err(format!("mod '{id}' is not loaded"));
+        }
+        if let Some(id) = self.not_mods.iter().find(|x| mods.contains(*x)) {
+            return Err(format!("mod '{id}' is loaded"));
+        }
+
+        for (name, expected) in &self.vars {
+            match var(name) {
+                Some(value) if value == *expected => (),
+                Some(value) => return Err(format!("var '{name}' is '{value}', not '{expected}'")),
+                None => return Err(format!("var '{name}' is not set")),
+            }
+        }
+
+        if let Some(name) = self.env.iter().find(|x| env::var_os(x).is_none()) {
+            return Err(format!("environment variable '{name}' is not set"));
+        }
+
+        if !self.os.is_empty() && !self.os.iter().any(|x| x == env::consts::OS) {
+            return Err(format!("running on {}, not {}", env::consts::OS, self.os.join(" or ")));
+        }
+
+        if let Some(req) = &self.lovely_version {
+            let version = Version::parse(LOVELY_VERSION).unwrap();
+            if !req.matches(&version) {
+                return Err(format!("Lovely {version} does not match '{req}'"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn checks_every_condition() {
+        let mods = HashSet::from(["steamodded".to_string()]);
+        let vars = HashMap::from([("MODE".to_string(), "hard".to_string())]);
+        let check = |x: Condition| x.check(&mods, |name| vars.get(name).map(String::as_str));
+
+        assert!(check(Condition::default()).is_ok());
+        assert!(check(Condition {
+            mods: vec!["steamodded".to_string()],
+            vars: BTreeMap::from([("MODE".to_string(), "hard".to_string())]),
+            os: vec![env::consts::OS.to_string()],
+            lovely_version: Some(VersionReq::parse(">=0.1.0").unwrap()),
+            ..Default::default()
+        })
+        .is_ok());
+
+        let missing = check(Condition {
+            mods: vec!["talisman".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(missing.unwrap_err(), "mod 'talisman' is not loaded");
+
+        let conflicting = check(Condition {
+            not_mods: vec!["steamodded".to_string()],
+            ..Default::default()
+        });
+        assert!(conflicting.is_err());
+
+        let wrong_var = check(Condition {
+            vars: BTreeMap::from([("MODE".to_string(), "easy".to_string())]),
+            ..Default::default()
+        });
+        assert_eq!(wrong_var.unwrap_err(), "var 'MODE' is 'hard', not 'easy'");
+
+        let unset_env = check(Condition {
+            env: vec!["LOVELY_TEST_UNSET_VARIABLE".to_string()],
+            ..Default::default()
+        });
+        assert!(unset_env.is_err());
+
+        let future = check(Condition {
+            lovely_version: Some(VersionReq::parse(">=100.0.0").unwrap()),
+            ..Default::default()
+        });
+        assert!(future.is_err());
+    }
+}
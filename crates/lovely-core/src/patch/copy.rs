@@ -1,7 +1,8 @@
-use super::Target;
+use super::{PatchMeta, Target};
 use crop::Rope;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use crate::dump::{ByteDebugEntry, ByteRegion, PatchSource, DebugPatchType};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,12 +20,13 @@ pub struct CopyPatch {
 
     pub payload: Option<String>,
 
-    // Currently unused.
-    pub name: Option<String>,
+    #[serde(flatten)]
+    pub meta: PatchMeta,
 
     // Buffer contents read at load time. We do this to support zip-based mods that we can't arbitrarily read from.
+    // Shared with every other patch copying the same source.
     #[serde(skip)]
-    pub contents: Vec<String>,
+    pub contents: Vec<Arc<str>>,
 }
 
 impl CopyPatch {
@@ -45,7 +47,7 @@ impl CopyPatch {
         let payloads = self
             .contents
             .iter()
-            .map(|s| s.as_str())
+            .map(|s| &**s)
             .chain(self.payload.as_deref().into_iter());
 
         for content in payloads {
@@ -79,6 +81,7 @@ impl CopyPatch {
             },
             regions: byte_regions,
             warnings: None,
+            matches: None,
         })
     }
 }
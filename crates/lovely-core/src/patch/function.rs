@@ -0,0 +1,280 @@
+use std::path::Path;
+
+use crop::Rope;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::dump::{ByteDebugEntry, ByteRegion, DebugPatchType, PatchSource};
+use crate::lua_parser::{self, FunctionDef};
+
+use super::{PatchMeta, Target};
+
+// The name the original body is bound to by `position = "wrap"`.
+pub const WRAPPED_BODY: &str = "__lovely_original";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum FunctionPosition {
+    // Insert the payload at the start of the function body.
+    Before,
+    // Insert the payload at the end of the function body, right before its `end`.
+    After,
+    // Move the original body into a local function named `__lovely_original`, and run the
+    // payload in its place.
+    Wrap,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FunctionPatch {
+    pub target: Target,
+
+    // The qualified name of the function, e.g. `Card:calculate_joker` or `G.FUNCS.play_cards`.
+    // Functions in table constructors are named after the table, e.g. `M.update` for
+    // `M = { update = function() end }`. `:` and `.` are interchangeable.
+    pub function: String,
+
+    pub position: FunctionPosition,
+    pub payload: String,
+
+    // Apply patch at most `times` times, warn if the number of matches differs from `times`.
+    pub times: Option<usize>,
+
+    #[serde(flatten)]
+    pub meta: PatchMeta,
+}
+
+impl FunctionPatch {
+    pub fn debug_from_warning_string(&self, path: &Path, warning: String) -> ByteDebugEntry {
+        log::warn!("{}", warning);
+        ByteDebugEntry {
+            patch_source: PatchSource {
+                file: path.display().to_string(),
+                pattern: Some(self.function.clone()),
+                patch_type: DebugPatchType::Function,
+            },
+            regions: Vec::new(),
+            warnings: Some(vec![warning.to_string()]),
+            matches: Some(0),
+        }
+    }
+
+    /// The insertions which apply this patch to a single definition, as (byte offset, text)
+    /// pairs into the unpatched source.
+    fn insertions(&self, source: &str, def: &FunctionDef) -> Vec<(usize, String)> {
+        let payload = self.payload.strip_suffix('\n').unwrap_or(&self.payload);
+
+        // Insert at the start of the line holding the closing `end` if nothing else is on it,
+        // otherwise right before the `end` on a line of its own.
+        let body_end = match source[..def.body_end].rfind('\n') {
+            Some(i) if i >= def.body_start && source[i + 1..def.body_end].trim().is_empty() => {
+                (i + 1, "")
+            }
+            _ => (def.body_end, "\n"),
+        };
+
+        match self.position {
+            FunctionPosition::Before => vec![(def.body_start, format!("\n{payload}\n"))],
+            FunctionPosition::After => vec![(body_end.0, format!("{}{payload}\n", body_end.1))],
+            FunctionPosition::Wrap => {
+                // `...` is a syntax error outside of vararg functions. The original body sees
+                // the parameters as upvalues, so it doesn't need them passed along.
+                let forward = format!("{WRAPPED_BODY}(...)");
+                let payload = match def.is_vararg {
+                    true => payload.to_string(),
+                    false => payload.replace(&forward, &format!("{WRAPPED_BODY}()")),
+                };
+                vec![
+                    (def.body_start, format!("\nlocal function {WRAPPED_BODY}(...)")),
+                    (body_end.0, format!("{}end\n{payload}\n", body_end.1)),
+                ]
+            }
+        }
+    }
+
+    /// Apply the function patch onto the rope.
+    /// Returns `Some(ByteDebugEntry)` if the patch targets this buffer, `None` otherwise.
+    pub fn apply(&self, target: &str, rope: &mut Rope, path: &Path) -> Option<ByteDebugEntry> {
+        if !self.target.can_apply(target) {
+            return None;
+        }
+
+        let source = rope.to_string();
+        let definitions = match lua_parser::parse(&source) {
+            Ok(x) => x,
+            Err(e) => {
+                let warning = format!(
+                    "Failed to parse target '{target}' for function patch from {}: {e}",
+                    path.display()
+                );
+                return Some(self.debug_from_warning_string(path, warning));
+            }
+        };
+
+        let mut matches = definitions
+            .iter()
+            .filter(|x| x.has_name(&self.function))
+            .collect_vec();
+        if matches.is_empty() {
+            let warning = format!(
+                "Function '{}' on target '{target}' for function patch from {} resulted in no matches",
+                self.function,
+                path.display()
+            );
+            return Some(self.debug_from_warning_string(path, warning));
+        }
+
+        let found_matches = matches.len();
+        let mut warnings = Vec::new();
+        if let Some(times) = self.times {
+            if found_matches != times {
+                let warning = format!(
+                    "Function '{}' on target '{target}' for function patch from {} resulted in {found_matches} matches, wanted {times}",
+                    self.function,
+                    path.display()
+                );
+                log::warn!("{warning}");
+                warnings.push(warning);
+            }
+            if found_matches > times {
+                log::warn!("Ignoring excess matches");
+                warnings.push("Ignoring excess matches".to_string());
+                matches.truncate(times);
+            }
+        }
+
+        // Every edit is an insertion, so applying them in source order only needs a running
+        // offset to stay valid.
+        let insertions = matches
+            .iter()
+            .flat_map(|x| self.insertions(&source, x))
+            .sorted_by_key(|(pos, _)| *pos);
+
+        let mut delta = 0;
+        let mut byte_regions = Vec::new();
+        for (pos, text) in insertions {
+            let start = pos + delta;
+            rope.insert(start, &text);
+            byte_regions.push(ByteRegion {
+                start,
+                end: start + text.len(),
+                delta: text.len() as isize,
+            });
+            delta += text.len();
+        }
+
+        Some(ByteDebugEntry {
+            patch_source: PatchSource {
+                file: path.display().to_string(),
+                pattern: Some(self.function.clone()),
+                patch_type: DebugPatchType::Function,
+            },
+            regions: byte_regions,
+            warnings: if warnings.is_empty() { None } else { Some(warnings) },
+            matches: Some(found_matches),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn patch(position: FunctionPosition, payload: &str) -> FunctionPatch {
+        FunctionPatch {
+            target: Target::Single("card.lua".into()),
+            function: "Card.calculate_joker".to_string(),
+            position,
+            payload: payload.to_string(),
+            times: None,
+            meta: PatchMeta::default(),
+        }
+    }
+
+    const SOURCE: &str = "function Card:calculate_joker(context)\n    return 1\nend\n";
+
+    fn apply(patch: &FunctionPatch, source: &str) -> (String, ByteDebugEntry) {
+        let mut rope = Rope::from(source);
+        let entry = patch
+            .apply("card.lua", &mut rope, &PathBuf::from("mod/lovely.toml"))
+            .unwrap();
+        (rope.to_string(), entry)
+    }
+
+    #[test]
+    fn inserts_before_and_after_body() {
+        let (patched, entry) = apply(&patch(FunctionPosition::Before, "print(context)"), SOURCE);
+        assert_eq!(
+            patched,
+            "function Card:calculate_joker(context)\nprint(context)\n\n    return 1\nend\n"
+        );
+        assert_eq!(entry.matches, Some(1));
+
+        let (patched, _) = apply(&patch(FunctionPosition::After, "-- done"), SOURCE);
+        assert_eq!(
+            patched,
+            "function Card:calculate_joker(context)\n    return 1\n-- done\nend\n"
+        );
+    }
+
+    #[test]
+    fn wraps_body() {
+        let payload = "local ret = __lovely_original()\nreturn ret + 1";
+        let (patched, entry) = apply(&patch(FunctionPosition::Wrap, payload), SOURCE);
+
+        assert_eq!(
+            patched,
+            "function Card:calculate_joker(context)\n\
+            local function __lovely_original(...)\n    return 1\n\
+            end\nlocal ret = __lovely_original()\nreturn ret + 1\n\
+            end\n"
+        );
+        assert_eq!(entry.regions.len(), 2);
+        assert!(lua_parser::parse(&patched).is_ok());
+    }
+
+    #[test]
+    fn survives_reformatting() {
+        let source = "Card.calculate_joker = function(self,context) return 1 end";
+        let (patched, _) = apply(&patch(FunctionPosition::Before, "local x = 2"), source);
+
+        assert_eq!(
+            patched,
+            "Card.calculate_joker = function(self,context)\nlocal x = 2\n return 1 end"
+        );
+    }
+
+    #[test]
+    fn payload_before_one_line_body_keeps_it_intact() {
+        let source = "function Card:calculate_joker(context) return 1 end\n";
+        let (patched, _) = apply(&patch(FunctionPosition::Before, "x = 1 -- set x"), source);
+
+        assert_eq!(
+            patched,
+            "function Card:calculate_joker(context)\nx = 1 -- set x\n return 1 end\n"
+        );
+        assert!(lua_parser::parse(&patched).is_ok());
+    }
+
+    #[test]
+    fn wrap_forwards_varargs_only_from_vararg_functions() {
+        let payload = "return __lovely_original(...)";
+        let (patched, _) = apply(&patch(FunctionPosition::Wrap, payload), SOURCE);
+        assert!(patched.contains("\nreturn __lovely_original()\n"));
+        assert!(lua_parser::parse(&patched).is_ok());
+
+        let source = "function Card:calculate_joker(...)\n    return 1\nend\n";
+        let (patched, _) = apply(&patch(FunctionPosition::Wrap, payload), source);
+        assert!(patched.contains("\nreturn __lovely_original(...)\n"));
+        assert!(lua_parser::parse(&patched).is_ok());
+    }
+
+    #[test]
+    fn missing_function_warns() {
+        let (patched, entry) = apply(&patch(FunctionPosition::Before, "x()"), "local y = 1\n");
+
+        assert_eq!(patched, "local y = 1\n");
+        assert_eq!(entry.matches, Some(0));
+        assert!(entry.warnings.unwrap()[0].contains("resulted in no matches"));
+    }
+}
@@ -1,23 +1,104 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Read;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-
-use crate::patch::{Patch, PatchFile, Priority};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::{panic, thread};
+
+use crate::patch::args::{ArgTable, DeclaredArg};
+use crate::patch::resolve::{resolve_mods, ModInfo};
+use crate::patch::vars::{self, Builtins, ModVars};
+use crate::patch::{LoadedPatch, Manifest, Patch, PatchFile, TargetSet};
+use crate::LOVELY_VERSION;
 use itertools::Itertools;
 use log::*;
+use semver::Version;
 use walkdir::WalkDir;
 use zip::ZipArchive;
 
-/// Patch file with preloaded TOML content and referenced sources
+// Replaced by {{lovely:MOD_DIR}}, still accepted in older patch files.
+const LEGACY_PATCH_DIR: &str = "{{lovely_hack:patch_dir}}";
+
+/// Patch file with preloaded TOML content
 #[derive(Debug)]
 struct IntermediatePatch {
     pub path: PathBuf,
     pub content: String,
-    /// Preloaded source files referenced by module/copy patches (relative path -> content)
-    pub sources: HashMap<PathBuf, String>,
+    /// Sources referenced by module/copy patches, shared by every patch file of the mod
+    pub sources: Arc<SourceStore>,
+}
+
+/// Where the sources of a mod are read from.
+enum SourceOrigin {
+    Dir(PathBuf),
+    Zip {
+        path: PathBuf,
+        archive: Mutex<ZipArchive<fs::File>>,
+        // Prefix of the mod root within the archive, with a trailing slash.
+        root: String,
+    },
+}
+
+/// Sources referenced by the module and copy patches of a mod, keyed by their path relative to
+/// the mod root. Each source is read once, the first time a patch file asks for it, and the
+/// contents are shared by every patch referencing it.
+struct SourceStore {
+    origin: SourceOrigin,
+    loaded: Mutex<HashMap<PathBuf, Arc<str>>>,
+}
+
+impl std::fmt::Debug for SourceStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let root = match &self.origin {
+            SourceOrigin::Dir(path) => path,
+            SourceOrigin::Zip { path, .. } => path,
+        };
+        f.debug_struct("SourceStore").field("root", root).finish_non_exhaustive()
+    }
+}
+
+impl SourceStore {
+    fn new(origin: SourceOrigin) -> Arc<Self> {
+        Arc::new(Self {
+            origin,
+            loaded: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the contents of a source, reading it if no patch file has asked for it yet.
+    fn get(&self, source: &Path) -> Result<Arc<str>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some(content) = loaded.get(source) {
+            return Ok(Arc::clone(content));
+        }
+
+        let content = match &self.origin {
+            SourceOrigin::Dir(mod_dir) => {
+                let full_path = mod_dir.join(source);
+                fs::read_to_string(&full_path)
+                    .with_context(|| format!("Failed to read source at {full_path:?}"))?
+            }
+            SourceOrigin::Zip { path, archive, root } => {
+                let name = format!("{root}{}", source.to_string_lossy());
+                let mut archive = archive.lock().unwrap();
+                let mut file = archive
+                    .by_name(&name)
+                    .with_context(|| format!("Failed to find source {name} in zip {path:?}"))?;
+                let mut content = String::new();
+                file.read_to_string(&mut content)
+                    .with_context(|| format!("Failed to read source {name} from zip {path:?}"))?;
+                content
+            }
+        };
+
+        let content: Arc<str> = content.into();
+        loaded.insert(source.to_path_buf(), Arc::clone(&content));
+        Ok(content)
+    }
 }
 
 /// Compare two file paths by their lowercase filenames
@@ -49,42 +130,17 @@ fn get_dir_patches(mod_dir: &Path) -> Result<(PathBuf, Vec<IntermediatePatch>)>
         toml_files.append(&mut subfiles);
     }
 
+    let sources = SourceStore::new(SourceOrigin::Dir(mod_dir.to_path_buf()));
     let intermediate_patches = toml_files
         .into_iter()
         .map(|toml_path| {
             let content = fs::read_to_string(&toml_path)
                 .with_context(|| format!("Failed to read patch file at {:?}", toml_path))?;
 
-            // Parse TOML to find referenced sources and preload them
-            let mut sources: HashMap<PathBuf, String> = HashMap::new();
-            let file_identifier = format!("{:?}", toml_path);
-            if let Ok(patch_file) = parse_patch_file(&content, &file_identifier, mod_dir) {
-                for patch in &patch_file.patches {
-                    match patch {
-                        Patch::Module(x) => {
-                            let full_path = mod_dir.join(&x.source);
-                            if let Ok(source_content) = fs::read_to_string(&full_path) {
-                                sources.insert(x.source.clone(), source_content);
-                            }
-                        }
-                        Patch::Copy(x) => {
-                            let Some(ref copy_sources) = x.sources else { continue };
-                            for source in copy_sources {
-                                let full_path = mod_dir.join(source);
-                                if let Ok(source_content) = fs::read_to_string(&full_path) {
-                                    sources.insert(source.clone(), source_content);
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-            }
-
             Ok(IntermediatePatch {
                 path: toml_path,
                 content,
-                sources,
+                sources: Arc::clone(&sources),
             })
         })
         .collect::<Result<Vec<IntermediatePatch>>>()?;
@@ -150,13 +206,10 @@ fn get_zip_patches(zip_file: &Path) -> Result<(PathBuf, Vec<IntermediatePatch>)>
         filename_cmp(a_path, b_path)
     });
 
-    // First pass: read all TOML files and parse them to find referenced sources
     let mut toml_contents: Vec<(String, String)> = Vec::new();
-    let mut source_paths: HashSet<String> = HashSet::new();
-
-    for toml_path in &toml_paths {
+    for toml_path in toml_paths {
         let mut file = zip
-            .by_name(toml_path)
+            .by_name(&toml_path)
             .with_context(|| format!("Failed to read {} from zip {:?}", toml_path, zip_file))?;
 
         let mut content = String::new();
@@ -164,44 +217,16 @@ fn get_zip_patches(zip_file: &Path) -> Result<(PathBuf, Vec<IntermediatePatch>)>
             format!("Failed to read contents of {} from zip {:?}", toml_path, zip_file)
         })?;
 
-        // Parse TOML to find referenced module/copy sources
-        let file_identifier = format!("{} from zip {:?}", toml_path, zip_file);
-        if let Ok(patch_file) = parse_patch_file(&content, &file_identifier, zip_file) {
-            for patch in &patch_file.patches {
-                match patch {
-                    Patch::Module(x) => {
-                        let source_path = format!("{}{}", mod_root, x.source.to_string_lossy());
-                        source_paths.insert(source_path);
-                    }
-                    Patch::Copy(x) => {
-                        if let Some(ref sources) = x.sources {
-                            for source in sources {
-                                let source_path = format!("{}{}", mod_root, source.to_string_lossy());
-                                source_paths.insert(source_path);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
-        }
-
-        toml_contents.push((toml_path.clone(), content));
+        toml_contents.push((toml_path, content));
     }
 
-    // Second pass: read all referenced source files from zip
-    let mut file_contents: HashMap<String, String> = HashMap::new();
-    for source_path in &source_paths {
-        if let Ok(mut file) = zip.by_name(source_path) {
-            let mut content = String::new();
-            file.read_to_string(&mut content).with_context(|| {
-                format!("Failed to read source {} from zip {:?}", source_path, zip_file)
-            })?;
-            file_contents.insert(source_path.clone(), content);
-        }
-    }
+    // Keep the archive open, sources are only read once a patch file asks for them.
+    let sources = SourceStore::new(SourceOrigin::Zip {
+        path: zip_file.to_path_buf(),
+        archive: Mutex::new(zip),
+        root: mod_root.clone(),
+    });
 
-    // Build IntermediatePatches with preloaded sources
     let intermediate_patches = toml_contents
         .into_iter()
         .map(|(toml_path, content)| {
@@ -209,17 +234,10 @@ fn get_zip_patches(zip_file: &Path) -> Result<(PathBuf, Vec<IntermediatePatch>)>
             let relative_path = &toml_path[mod_root.len()..];
             let intermediate_path = zip_file.join(relative_path);
 
-            // Build sources map with paths relative to mod_root
-            let mut sources: HashMap<PathBuf, String> = HashMap::new();
-            for (full_path, content) in &file_contents {
-                let relative = &full_path[mod_root.len()..];
-                sources.insert(PathBuf::from(relative), content.clone());
-            }
-
             IntermediatePatch {
                 path: intermediate_path,
                 content,
-                sources,
+                sources: Arc::clone(&sources),
             }
         })
         .collect();
@@ -227,6 +245,91 @@ fn get_zip_patches(zip_file: &Path) -> Result<(PathBuf, Vec<IntermediatePatch>)>
     Ok((zip_file.to_path_buf(), intermediate_patches))
 }
 
+/// Patches of every mod which passed dependency resolution, along with the ids of those mods.
+#[derive(Debug, Default)]
+pub struct LoadedMods {
+    pub patches: Vec<(LoadedPatch, HashMap<String, String>)>,
+    // Names, ids and provided ids of the loaded mods, as matched by `mods` in patch conditions.
+    pub ids: HashSet<String>,
+    // Command line arguments declared by the loaded patch files.
+    pub args: Vec<DeclaredArg>,
+}
+
+impl LoadedMods {
+    /// Drop the patches of a mod after all, and its id from the ids seen by conditions.
+    pub fn skip_mod(&mut self, mod_id: &str) {
+        self.patches.retain(|(x, _)| x.mod_id != mod_id);
+        self.ids.remove(mod_id);
+    }
+}
+
+// The patch files of a mod, each with the built-in vars its patches were resolved against.
+type ModFiles = Vec<(IntermediatePatch, PatchFile, Arc<Builtins>)>;
+
+/// Read and parse every patch file of a directory or zip mod.
+fn load_mod(path: &Path) -> Result<(ModInfo, ModFiles)> {
+    let (base_path, ips) = if path.is_dir() {
+        get_dir_patches(path)?
+    } else {
+        get_zip_patches(path)?
+    };
+
+    let mut info = ModInfo::new(&base_path);
+    let mut parsed = Vec::new();
+    for ip in ips {
+        let file_identifier = format!("{:?}", ip.path);
+        let file = parse_patch_file(&ip.content, &file_identifier)?;
+        info.merge_manifest(file.manifest(), &ip.path);
+        parsed.push((ip, file_identifier, file));
+    }
+
+    // Built-in vars are resolved once every manifest is merged, so that `MOD_ID` is the id the
+    // mod's patches are loaded under, whichever of its files declares it.
+    let mod_id = info.id.as_ref().unwrap_or(&info.name);
+    let mut files = Vec::new();
+    for (ip, file_identifier, file) in parsed {
+        let patch_path = ip.path.strip_prefix(&info.path).unwrap_or(&ip.path);
+        let builtins = Arc::new(Builtins::new(mod_id, &info.path, patch_path));
+        let patch_file = file.resolve(&builtins, &file_identifier)?;
+        warn_unknown_keys(&patch_file, &file_identifier);
+        files.push((ip, patch_file, builtins));
+    }
+
+    Ok((info, files))
+}
+
+/// Apply `f` to every item on a pool of scoped worker threads. Results are returned in the
+/// order of `items`.
+fn par_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let workers = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(items.len());
+    if workers <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let worker = || {
+        let mut done = Vec::new();
+        loop {
+            let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+            let Some(item) = items.get(i) else { break };
+            done.push((i, f(item)));
+        }
+        done
+    };
+
+    let mut results = thread::scope(|s| {
+        let handles = (0..workers).map(|_| s.spawn(worker)).collect_vec();
+        handles
+            .into_iter()
+            .flat_map(|x| x.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect_vec()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, x)| x).collect()
+}
+
 /// Load patches from the provided mod directory. This scans for lovely patch files
 /// within each subdirectory that matches either:
 /// - MOD_DIR/lovely.toml
@@ -234,7 +337,7 @@ fn get_zip_patches(zip_file: &Path) -> Result<(PathBuf, Vec<IntermediatePatch>)>
 /// 
 /// Zip archives are supported and uniquely support directory nesting 
 /// (i.e., mod.zip/dir/lovely.toml), but otherwise are treated the same as dir mods.
-pub fn load_patches_new(mod_dir: &Path) -> Result<Vec<(Patch, Priority, PathBuf, HashMap<String, String>)>> {
+pub fn load_patches_new(mod_dir: &Path) -> Result<LoadedMods> {
     let blacklist_file = mod_dir.join("lovely").join("blacklist.txt");
 
     let mut blacklist: HashSet<String> = HashSet::new();
@@ -265,8 +368,8 @@ pub fn load_patches_new(mod_dir: &Path) -> Result<Vec<(Patch, Priority, PathBuf,
         })
         .collect_vec();
 
-    // Collect directory patches (read TOMLs into IntermediatePatch)
-    let dir_results: Vec<(PathBuf, Vec<IntermediatePatch>)> = mod_contents
+    // Directory mods, then zip mods, each sorted by name.
+    let dir_mods = mod_contents
         .iter()
         .filter(|x| x.is_dir())
         .filter(|x| {
@@ -280,32 +383,57 @@ pub fn load_patches_new(mod_dir: &Path) -> Result<Vec<(Patch, Priority, PathBuf,
             }
             !ignore_file.is_file()
         })
-        .sorted_by(|a, b| filename_cmp(a, b))
-        .map(|x| get_dir_patches(x))
-        .collect::<Result<Vec<_>>>()?;
-
-    // Collect zip patches (read TOMLs into IntermediatePatch)
-    let zip_results: Vec<(PathBuf, Vec<IntermediatePatch>)> = mod_contents
+        .sorted_by(|a, b| filename_cmp(a, b));
+    let zip_mods = mod_contents
         .iter()
         .filter(|x| x.is_file())
         .filter(|x| x.extension().is_some_and(|ext| ext == "zip"))
-        .sorted_by(|a, b| filename_cmp(a, b))
-        .map(|x| get_zip_patches(x))
-        .collect::<Result<Vec<_>>>()?;
-
-    // Parse TOML contents into PatchFile structures
-    let mut patches: Vec<(Patch, Priority, PathBuf, HashMap<String, String>)> = Vec::new();
-
-    // Handle all patch files using preloaded sources
-    let all_results = dir_results.into_iter().chain(zip_results.into_iter());
-
-    for (base_path, ips) in all_results {
-        for ip in ips {
-            let file_identifier = format!("{:?}", ip.path);
-            let mut patch_file: PatchFile = parse_patch_file(&ip.content, &file_identifier, &base_path)?;
-
+        .sorted_by(|a, b| filename_cmp(a, b));
+    let mod_paths = dir_mods.chain(zip_mods).collect_vec();
+
+    // Read and parse every patch file up front, one mod per task, so that mod manifests can be
+    // resolved before any of their patches are accepted. Results keep the order of `mod_paths`,
+    // and the first error in that order is reported, regardless of scheduling.
+    let (mods, mut parsed): (Vec<ModInfo>, Vec<Option<ModFiles>>) =
+        par_map(&mod_paths, |x| load_mod(x))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .map(|(info, files)| (info, Some(files)))
+            .unzip();
+
+    let mut loaded_mods = LoadedMods::default();
+
+    // Skip incompatible mods and mods with unmet dependencies, and load the rest after their
+    // dependencies.
+    let lovely_version = Version::parse(LOVELY_VERSION).unwrap();
+    for (mod_index, min_priority) in resolve_mods(&mods, &lovely_version) {
+        let files = parsed[mod_index].take().unwrap();
+        let info = &mods[mod_index];
+        let mod_id = info.id.as_ref().unwrap_or(&info.name);
+        loaded_mods.ids.insert(info.name.clone());
+        loaded_mods.ids.extend(info.id.iter().chain(&info.provides).cloned());
+
+        for (ip, mut patch_file, builtins) in files {
             // For module and copy patches, use preloaded sources
             for patch in &mut patch_file.patches {
+                // Compile matchers once, rather than for every target they are applied to.
+                if let Patch::Pattern(ref mut x) = patch {
+                    x.compile();
+                }
+                if let Patch::Range(ref mut x) = patch {
+                    x.compile();
+                }
+                if let Patch::Regex(ref mut x) = patch {
+                    x.compile().map_err(|e| {
+                        anyhow!(
+                            "Error at patch file {}:\nFailed to compile regex '{}': {e}",
+                            ip.path.display(),
+                            x.pattern.escape_debug()
+                        )
+                    })?;
+                }
+
                 if let Patch::Module(ref mut x) = patch {
                     if x.load_now && x.before.is_none() {
                         bail!(
@@ -318,11 +446,10 @@ pub fn load_patches_new(mod_dir: &Path) -> Result<Vec<(Patch, Priority, PathBuf,
                     x.display_source = x.source.to_string_lossy().to_string();
                     x.content = ip.sources.get(&x.source)
                         .with_context(|| format!(
-                            "Module source {:?} not found in preloaded sources for patch from {}",
+                            "Module source {:?} not found for patch from {}",
                             x.source,
                             ip.path.display()
-                        ))?
-                        .clone();
+                        ))?;
                 }
 
                 let Patch::Copy(ref mut x) = patch else { continue };
@@ -331,15 +458,25 @@ pub fn load_patches_new(mod_dir: &Path) -> Result<Vec<(Patch, Priority, PathBuf,
                 for source in sources {
                     let source_content = ip.sources.get(source)
                         .with_context(|| format!(
-                            "Copy source {:?} not found in preloaded sources for patch from {}",
+                            "Copy source {:?} not found for patch from {}",
                             source,
                             ip.path.display()
                         ))?;
-                    x.contents.push(source_content.clone());
+                    x.contents.push(source_content);
                 }
             }
 
-            let priority = patch_file.manifest.priority;
+            let priority = patch_file.manifest.priority.max(min_priority);
+            if priority != patch_file.manifest.priority {
+                info!(
+                    "Raised priority of patch file {} from {} to {priority} so that it applies after its dependencies",
+                    ip.path.display(),
+                    patch_file.manifest.priority
+                );
+            }
+            let strict = patch_file.manifest.strict;
+            let unified = patch_file.manifest.unified;
+            let game_version = patch_file.manifest.game_version;
             let vars = patch_file.vars;
 
             // mod_relative_path: path relative to top-level mod_dir
@@ -351,72 +488,174 @@ pub fn load_patches_new(mod_dir: &Path) -> Result<Vec<(Patch, Priority, PathBuf,
                 )
             })?;
 
+            loaded_mods.args.extend(patch_file.args.into_iter().map(|(key, spec)| DeclaredArg {
+                mod_id: mod_id.clone(),
+                key,
+                path: mod_relative_path.to_path_buf(),
+                spec,
+            }));
+
             let patches_vec = patch_file
                 .patches
                 .into_iter()
-                .map(|patch| (patch, priority, mod_relative_path.to_path_buf(), vars.clone()));
-
-            patches.extend(patches_vec);
+                .map(|patch| {
+                    let loaded = LoadedPatch {
+                        patch,
+                        priority,
+                        path: mod_relative_path.to_path_buf(),
+                        strict,
+                        unified,
+                        game_version: game_version.clone(),
+                        mod_id: mod_id.clone(),
+                        builtins: Arc::clone(&builtins),
+                    };
+                    (loaded, vars.clone())
+                });
+
+            loaded_mods.patches.extend(patches_vec);
         }
     }
 
-    Ok(patches)
+    Ok(loaded_mods)
 }
 
-/// Process raw patches to extract targets and consolidate variables
+/// Process raw patches to extract targets and collect the vars of each mod, including the values
+/// of their command line arguments. Patches whose `if` conditions don't hold are dropped here,
+/// before they enter the patch table.
 pub fn process_patches(
-    raw_patches: Vec<(Patch, Priority, PathBuf, HashMap<String, String>)>,
+    raw: LoadedMods,
+    args: &ArgTable,
 ) -> (
-    Vec<(Patch, Priority, PathBuf)>,
-    HashSet<String>,
-    HashMap<String, String>,
+    Vec<LoadedPatch>,
+    TargetSet,
+    ModVars,
 ) {
-    let mut targets: HashSet<String> = HashSet::new();
-    let mut patches: Vec<(Patch, Priority, PathBuf)> = Vec::new();
-    let mut var_table: HashMap<String, String> = HashMap::new();
+    let mut targets = TargetSet::default();
+    let mut patches: Vec<LoadedPatch> = Vec::new();
+    let mut var_table = ModVars::default();
+
+    // Vars are scoped to the mod which declares them. Conditions see every var, regardless of
+    // which of the mod's patch files declares it.
+    for (loaded, vars) in &raw.patches {
+        for (name, value) in vars.iter().sorted() {
+            var_table.declare(&loaded.mod_id, name, value, &loaded.path);
+        }
+    }
+    // Arguments passed on the command line take precedence over the declared vars.
+    for (mod_id, key, value) in args.iter() {
+        var_table.insert(mod_id, key, &value.as_var());
+    }
 
-    for (patch, priority, path, vars) in raw_patches {
-        // Extract targets from patches
-        match &patch {
-            Patch::Copy(x) => {
-                x.target.insert_into(&mut targets);
-            }
-            Patch::Module(x) => {
-                targets.insert(x.before.clone().unwrap_or_default());
-            }
-            Patch::Pattern(x) => {
-                x.target.insert_into(&mut targets);
-            }
-            Patch::Regex(x) => {
-                x.target.insert_into(&mut targets);
+    for (loaded, _) in raw.patches {
+        if let Some(condition) = loaded.patch.condition() {
+            if let Err(reason) = condition.check(&raw.ids, |x| var_table.lookup(&loaded.mod_id, x)) {
+                info!(
+                    "Skipping {} patch from {}: {reason}",
+                    loaded.patch.type_name(),
+                    loaded.path.display()
+                );
+                continue;
             }
         }
 
-        // Add to final patches
-        patches.push((patch, priority, path));
+        // Extract targets from patches
+        if let Some(target) = loaded.patch.target() {
+            target.insert_into(&mut targets);
+        }
+        if let Patch::Module(x) = &loaded.patch {
+            targets.insert(x.before.as_deref().unwrap_or_default());
+        }
 
-        // Add variables (later ones override earlier ones)
-        var_table.extend(vars);
+        // Add to final patches
+        patches.push(loaded);
     }
 
     (patches, targets, var_table)
 }
 
-/// Parse TOML content into a PatchFile
-fn parse_patch_file(content: &str, file_identifier: &str, mod_dir: &Path) -> Result<PatchFile> {
-    // HACK: Replace instances of {{lovely_hack:patch_dir}} with mod directory.
-    let clean_mod_dir = &mod_dir.to_string_lossy().replace("\\", "\\\\");
-    let content = content.replace("{{lovely_hack:patch_dir}}", clean_mod_dir);
+/// A parsed patch file. Files which use built-in vars are kept as TOML until the mod id is
+/// known, see `ParsedFile::resolve`.
+enum ParsedFile {
+    Ready(PatchFile),
+    NeedsBuiltins(Manifest, toml::Value),
+}
+
+impl ParsedFile {
+    fn manifest(&self) -> &Manifest {
+        match self {
+            ParsedFile::Ready(x) => &x.manifest,
+            ParsedFile::NeedsBuiltins(manifest, _) => manifest,
+        }
+    }
+
+    /// Resolve built-in vars in the parsed string values, so that their values never need TOML
+    /// escaping, and deserialize the result.
+    fn resolve(self, builtins: &Builtins, file_identifier: &str) -> Result<PatchFile> {
+        let mut value = match self {
+            ParsedFile::Ready(x) => return Ok(x),
+            ParsedFile::NeedsBuiltins(_, value) => value,
+        };
+        builtins
+            .resolve_in(&mut value)
+            .map_err(|e| anyhow!("Error at patch file {file_identifier}:\n{e}"))?;
+        deserialize_patch_file(value, file_identifier)
+    }
+}
+
+/// Parse TOML content. Only the manifest of files which use built-in vars is deserialized here.
+fn parse_patch_file(content: &str, file_identifier: &str) -> Result<ParsedFile> {
+    let mut content = content.to_string();
+    if content.contains(LEGACY_PATCH_DIR) {
+        warn!(
+            "Patch file {file_identifier} uses {LEGACY_PATCH_DIR}, which is deprecated. \
+            Use {{{{lovely:MOD_DIR}}}} instead"
+        );
+        content = content.replace(LEGACY_PATCH_DIR, "{{lovely:MOD_DIR}}");
+    }
 
+    if !vars::uses_builtins(&content) {
+        let deserializer = toml::Deserializer::new(&content);
+        return Ok(ParsedFile::Ready(deserialize_patch_file(deserializer, file_identifier)?));
+    }
+
+    let value: toml::Value = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse patch file {file_identifier}"))?;
+    let manifest = value
+        .get("manifest")
+        .cloned()
+        .context("missing field `manifest`")
+        .and_then(|x| Ok(x.try_into::<Manifest>()?))
+        .with_context(|| format!("Failed to parse patch file {file_identifier}"))?;
+    Ok(ParsedFile::NeedsBuiltins(manifest, value))
+}
+
+/// Deserialize a patch file, warning about keys which no field claims.
+fn deserialize_patch_file<'de, D>(deserializer: D, file_identifier: &str) -> Result<PatchFile>
+where
+    D: serde::Deserializer<'de>,
+    D::Error: Send + Sync + 'static,
+{
     let ignored_key_callback = |key: serde_ignored::Path| {
         warn!("Unknown key `{key}` found in patch file {file_identifier}, ignoring it");
     };
 
-    serde_ignored::deserialize(toml::Deserializer::new(&content), ignored_key_callback)
+    serde_ignored::deserialize(deserializer, ignored_key_callback)
         .with_context(|| format!("Failed to parse patch file {file_identifier}"))
 }
 
-
+/// Warn about keys of patches which no field claims. The fields shared by every patch type are
+/// flattened into it, which hides unknown keys from `serde_ignored`.
+fn warn_unknown_keys(patch_file: &PatchFile, file_identifier: &str) {
+    for (i, patch) in patch_file.patches.iter().enumerate() {
+        for key in patch.meta().into_iter().flat_map(|x| x.unknown.keys()) {
+            warn!(
+                "Unknown key `patches.{i}.{}.{key}` found in patch file {file_identifier}, \
+                ignoring it",
+                patch.type_name()
+            );
+        }
+    }
+}
 
 /// Helper to extract parent directory path with trailing slash
 fn get_parent(path: &str) -> String {
@@ -429,6 +668,7 @@ fn get_parent(path: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::patch::Target;
     use std::io::Write;
     use tempfile::TempDir;
     use zip::write::SimpleFileOptions;
@@ -467,7 +707,7 @@ sources = ["inject.lua"]
         let (_, patches) = get_dir_patches(&mod_dir).unwrap();
 
         assert_eq!(patches.len(), 1);
-        assert_eq!(patches[0].sources.get(Path::new("inject.lua")).unwrap(), "-- injected");
+        assert_eq!(&*patches[0].sources.get(Path::new("inject.lua")).unwrap(), "-- injected");
     }
 
     #[test]
@@ -481,7 +721,32 @@ sources = ["inject.lua"]
         let (_, patches) = get_zip_patches(&zip).unwrap();
 
         assert_eq!(patches.len(), 1);
-        assert_eq!(patches[0].sources.get(Path::new("inject.lua")).unwrap(), "-- from zip");
+        assert_eq!(&*patches[0].sources.get(Path::new("inject.lua")).unwrap(), "-- from zip");
+    }
+
+    #[test]
+    fn sources_are_shared_between_patch_files() {
+        let temp = TempDir::new().unwrap();
+        let mods = temp.path();
+        fs::create_dir_all(mods.join("lovely")).unwrap();
+        make_zip(&temp, "mod.zip", &[
+            ("lovely/a.toml", PATCH_TOML),
+            ("lovely/b.toml", PATCH_TOML),
+            ("inject.lua", "-- shared"),
+        ]);
+
+        let patches = load_patches_new(mods).unwrap().patches;
+        let contents = patches
+            .iter()
+            .map(|(x, _)| match &x.patch {
+                Patch::Copy(x) => Arc::clone(&x.contents[0]),
+                _ => unreachable!(),
+            })
+            .collect_vec();
+
+        assert_eq!(contents.len(), 2);
+        assert_eq!(&*contents[0], "-- shared");
+        assert!(Arc::ptr_eq(&contents[0], &contents[1]));
     }
 
     #[test]
@@ -495,7 +760,7 @@ sources = ["inject.lua"]
         let (_, patches) = get_zip_patches(&zip).unwrap();
 
         assert_eq!(patches.len(), 1);
-        assert_eq!(patches[0].sources.get(Path::new("inject.lua")).unwrap(), "-- nested");
+        assert_eq!(&*patches[0].sources.get(Path::new("inject.lua")).unwrap(), "-- nested");
     }
 
     #[test]
@@ -535,10 +800,88 @@ sources = ["inject.lua"]
         ]);
         fs::rename(temp.path().join("blocked.zip"), mods.join("blocked.zip")).unwrap();
 
-        let patches = load_patches_new(mods).unwrap();
+        let patches = load_patches_new(mods).unwrap().patches;
 
         assert_eq!(patches.len(), 1);
-        assert!(patches[0].2.to_string_lossy().contains("allowed"));
+        assert!(patches[0].0.path.to_string_lossy().contains("allowed"));
+    }
+
+    #[test]
+    fn dependencies_order_and_skip_mods() {
+        let temp = TempDir::new().unwrap();
+        let mods = temp.path();
+        fs::create_dir_all(mods.join("lovely")).unwrap();
+
+        // "a_dependent" sorts first by name, but must load after "b_base".
+        for (dir, manifest) in [
+            ("a_dependent", "id = \"dependent\"\ndependencies = { base = \">=1.0.0\" }"),
+            ("b_base", "id = \"base\""),
+            ("c_orphan", "id = \"orphan\"\ndependencies = { missing = \"*\" }"),
+        ] {
+            let m = mods.join(dir);
+            fs::create_dir_all(&m).unwrap();
+            fs::write(m.join("lovely.toml"), format!(r#"
+[manifest]
+version = "1.0.0"
+{manifest}
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "append"
+payload = "-- {dir}"
+"#)).unwrap();
+        }
+
+        let patches = load_patches_new(mods).unwrap().patches;
+        let dirs = patches
+            .iter()
+            .map(|(x, _)| x.path.parent().unwrap().to_string_lossy().to_string())
+            .collect_vec();
+
+        assert_eq!(dirs, vec!["b_base", "a_dependent"]);
+    }
+
+    #[test]
+    fn parallel_load_keeps_mod_order() {
+        let temp = TempDir::new().unwrap();
+        let mods = temp.path();
+        fs::create_dir_all(mods.join("lovely")).unwrap();
+
+        let toml = |name: &str| format!(r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "append"
+payload = "-- {name}"
+"#);
+        let mut expected = Vec::new();
+        for i in 0..32 {
+            let name = format!("dir_{i:02}");
+            let m = mods.join(&name);
+            fs::create_dir_all(&m).unwrap();
+            fs::write(m.join("lovely.toml"), toml(&name)).unwrap();
+            expected.push(name);
+        }
+        for i in 0..8 {
+            let name = format!("zip_{i:02}");
+            make_zip(&temp, &format!("{name}.zip"), &[("lovely.toml", &toml(&name))]);
+            expected.push(name);
+        }
+
+        let patches = load_patches_new(mods).unwrap().patches;
+        let names = patches
+            .iter()
+            .map(|(x, _)| match &x.patch {
+                Patch::Copy(x) => x.payload.as_deref().unwrap().trim_start_matches("-- ").to_string(),
+                _ => unreachable!(),
+            })
+            .collect_vec();
+
+        assert_eq!(names, expected);
     }
 
     #[test]
@@ -553,7 +896,7 @@ sources = ["inject.lua"]
         fs::write(ignored.join("inject.lua"), "").unwrap();
         fs::write(ignored.join(".lovelyignore"), "").unwrap();
 
-        let patches = load_patches_new(mods).unwrap();
+        let patches = load_patches_new(mods).unwrap().patches;
         assert!(patches.is_empty());
     }
 
@@ -573,6 +916,28 @@ sources = ["inject.lua"]
         assert!(result.unwrap_err().to_string().contains("not found"));
     }
 
+    #[test]
+    fn invalid_regex_is_load_error() {
+        let temp = TempDir::new().unwrap();
+        let m = temp.path().join("mod");
+        fs::create_dir_all(&m).unwrap();
+        fs::write(m.join("lovely.toml"), r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.regex]
+target = "main.lua"
+pattern = "(unclosed"
+position = "at"
+payload = ""
+"#).unwrap();
+
+        let err = load_patches_new(temp.path()).unwrap_err().to_string();
+        assert!(err.contains("lovely.toml"));
+        assert!(err.contains("Failed to compile regex '(unclosed'"));
+    }
+
     #[test]
     fn lovely_subdir_tomls_loaded() {
         let temp = TempDir::new().unwrap();
@@ -602,7 +967,7 @@ position = "append"
 payload = "-- b"
 "#).unwrap();
 
-        let patches = load_patches_new(mods).unwrap();
+        let patches = load_patches_new(mods).unwrap().patches;
         assert_eq!(patches.len(), 2);
     }
 
@@ -629,11 +994,168 @@ payload = "-- hi"
 "#).unwrap();
 
         let raw = load_patches_new(mods).unwrap();
-        let (patches, targets, vars) = process_patches(raw);
+        let (patches, targets, vars) = process_patches(raw, &ArgTable::default());
 
         assert_eq!(patches.len(), 1);
         assert!(targets.contains("game.lua"));
-        assert_eq!(vars.get("FOO"), Some(&"bar".to_string()));
+        assert_eq!(vars.get("mod", "FOO"), Some("bar"));
+    }
+
+    #[test]
+    fn process_patches_skips_unmet_conditions() {
+        let temp = TempDir::new().unwrap();
+        let mods = temp.path();
+        fs::create_dir_all(mods.join("lovely")).unwrap();
+
+        let base = mods.join("base");
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("lovely.toml"), r#"
+[manifest]
+version = "1.0.0"
+id = "base"
+provides = ["api"]
+
+[vars]
+MODE = "hard"
+
+[[patches]]
+[patches.copy]
+target = "base.lua"
+position = "append"
+payload = "-- base"
+"#).unwrap();
+
+        let m = mods.join("mod");
+        fs::create_dir_all(&m).unwrap();
+        fs::write(m.join("lovely.toml"), r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.copy]
+target = "with_api.lua"
+position = "append"
+payload = "-- api"
+[patches.copy.if]
+mods = ["api"]
+vars = { "base.MODE" = "hard" }
+
+[[patches]]
+[patches.copy]
+target = "without_base.lua"
+position = "append"
+payload = "-- no base"
+[patches.copy.if]
+not_mods = ["base"]
+
+[[patches]]
+[patches.copy]
+target = "easy.lua"
+position = "append"
+payload = "-- easy"
+[patches.copy.if]
+vars = { MODE = "easy" }
+"#).unwrap();
+
+        let (patches, targets, _) =
+            process_patches(load_patches_new(mods).unwrap(), &ArgTable::default());
+
+        assert_eq!(patches.len(), 2);
+        assert!(targets.contains("with_api.lua"));
+        assert!(!targets.contains("without_base.lua"));
+        assert!(!targets.contains("easy.lua"));
+    }
+
+    #[test]
+    fn args_are_vars_of_their_mod() {
+        let temp = TempDir::new().unwrap();
+        let m = temp.path().join("debug-mod");
+        fs::create_dir_all(&m).unwrap();
+        fs::write(m.join("lovely.toml"), r#"
+[manifest]
+version = "1.0.0"
+id = "debug-mod"
+
+[args.debug_overlay]
+help = "Show the debug overlay"
+treat_as_flag = true
+
+[args.log_level]
+default = "info"
+
+[[patches]]
+[patches.copy]
+target = "overlay.lua"
+position = "append"
+payload = "-- overlay"
+[patches.copy.if]
+vars = { debug_overlay = "true" }
+"#).unwrap();
+
+        let mut raw = load_patches_new(temp.path()).unwrap();
+        assert_eq!(raw.args.len(), 2);
+        let argv = ["--debug-overlay".to_string(), "--log-level=warn".to_string()];
+        let args = ArgTable::parse(std::mem::take(&mut raw.args), &argv);
+        let (patches, targets, vars) = process_patches(raw, &args);
+
+        assert_eq!(patches.len(), 1);
+        assert!(targets.contains("overlay.lua"));
+        assert_eq!(vars.get("debug-mod", "log_level"), Some("warn"));
+        assert!(args.help().contains("Show the debug overlay"));
+    }
+
+    #[test]
+    fn builtin_vars_need_no_toml_escaping() {
+        let temp = TempDir::new().unwrap();
+        let m = temp.path().join("it's");
+        fs::create_dir_all(&m).unwrap();
+        fs::write(m.join("lovely.toml"), r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.copy]
+target = "{{lovely:MOD_ID}}.lua"
+position = "append"
+payload = 'return "{{lovely_hack:patch_dir}}", {{lovely:PATCH_FILE:lua}}'
+"#).unwrap();
+
+        let patches = load_patches_new(temp.path()).unwrap().patches;
+        let Patch::Copy(x) = &patches[0].0.patch else { unreachable!() };
+        let mod_dir = m.to_string_lossy().replace('\\', "/");
+
+        assert!(matches!(&x.target, Target::Single(x) if x == "it's.lua"));
+        assert_eq!(x.payload.as_deref().unwrap(), format!(r#"return "{mod_dir}", "lovely.toml""#));
+    }
+
+    #[test]
+    fn builtin_mod_id_is_the_id_of_the_whole_mod() {
+        let temp = TempDir::new().unwrap();
+        let m = temp.path().join("SomeMod");
+        fs::create_dir_all(m.join("lovely")).unwrap();
+        fs::write(m.join("lovely.toml"), r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "append"
+payload = "-- {{lovely:MOD_ID}}"
+"#).unwrap();
+        fs::write(m.join("lovely").join("id.toml"), r#"
+patches = []
+
+[manifest]
+version = "1.0.0"
+id = "some-mod"
+"#).unwrap();
+
+        let patches = load_patches_new(temp.path()).unwrap().patches;
+        let Patch::Copy(x) = &patches[0].0.patch else { unreachable!() };
+
+        assert_eq!(x.payload.as_deref().unwrap(), "-- some-mod");
+        assert_eq!(patches[0].0.builtins.mod_id, patches[0].0.mod_id);
     }
 
     #[test]
@@ -1,17 +1,36 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use serde::{Deserialize, Serialize};
+use crop::Rope;
+use regex_lite::Regex;
+use semver::VersionReq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use wildmatch::WildMatch;
 
+use crate::dump::{ByteDebugEntry, DebugPatchType};
+
+pub use args::PatchArgs;
+pub use condition::Condition;
 pub use copy::CopyPatch;
+pub use function::FunctionPatch;
 pub use module::ModulePatch;
 pub use pattern::PatternPatch;
+pub use range::RangePatch;
 pub use regex::RegexPatch;
+pub use vars::Builtins;
 
+pub mod args;
+pub mod condition;
 pub mod copy;
+pub mod function;
 pub mod loader;
 pub mod module;
 pub mod pattern;
+pub mod range;
 pub mod regex;
+pub mod resolve;
+pub mod suggest;
 pub mod table;
 pub mod vars;
 
@@ -25,6 +44,38 @@ pub struct Manifest {
     pub dump_lua: bool,
     #[serde(default)]
     pub priority: Priority,
+    // Turn pattern, regex, function and range patches which match nothing, or a number of times
+    // other than `times`, into hard errors instead of warnings.
+    #[serde(default)]
+    pub strict: bool,
+    // Don't hoist copy patches into a phase of their own. Copy, pattern, regex, function and range
+    // patches of this file are applied in priority and declaration order instead.
+    #[serde(default)]
+    pub unified: bool,
+
+    // Unique identifier of the mod this patch file belongs to. Other mods refer to it by this id
+    // in their dependencies and conflicts, and `version` is its version.
+    #[serde(default)]
+    pub id: Option<String>,
+    // Mod ids this mod requires, mapped to a semver range such as ">=1.2.0" or "*".
+    // The mod is skipped if any of them is missing or does not match.
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionReq>,
+    // Mod ids this mod is known to break, mapped to the semver range of affected versions.
+    #[serde(default)]
+    pub conflicts: BTreeMap<String, VersionReq>,
+    // Additional ids this mod satisfies dependencies on, at its own version.
+    #[serde(default)]
+    pub provides: Vec<String>,
+
+    // The semver range of Lovely versions this patch file works with, such as ">=0.9.0".
+    // The mod is skipped when the running Lovely version is out of range.
+    #[serde(default)]
+    pub lovely_version: Option<VersionReq>,
+    // The game version this patch file was written for. Informational only, it is included
+    // in warnings when one of the file's patches does not match.
+    #[serde(default)]
+    pub game_version: Option<String>,
 }
 
 // Represents a single .toml file after deserialization.
@@ -37,37 +88,147 @@ pub struct PatchFile {
     // into injected source code as the *last* step in the patching process.
     #[serde(default)]
     pub vars: HashMap<String, String>,
-    // // A table of arguments, read and parsed from the environment command line.
-    // // Binds double-hyphenated argument names (--arg) to a value, with additional metadata
-    // // available to produce help messages, set default values, and apply other behavior.
-    // #[serde(default)]
-    // pub args: HashMap<String, PatchArgs>,
-}
-
-// #[derive(Serialize, Deserialize, Debug)]
-// pub struct PatchArgs {
-//     // An optional help string. This will be printed out in the calling console
-//     // (if available) when the --help argument is supplied.
-//     pub help: Option<String>,
-
-//     // An optional default value. Not including a default value will cause Lovely
-//     // to panic if this argument is missing or could not be parsed.
-//     // Consider this to be both a "default value" and a "required" field, depending
-//     // on whether or not it's set.
-//     pub default: Option<String>,
-
-//     // This field allows for a patch author to force lovely to parse incoming arguments
-//     // with the exact name that they are defined by.
-//     // This disables lovely's automatic underscore to hyphen conversion.
-//     #[serde(default)]
-//     pub name_override: bool,
-
-//     // This field allows for arguments (--arg) to be passed without implicit values,
-//     // treating it essentially as a flag. If it exists in the args, it's true, if not,
-//     // then we set it to false.
-//     #[serde(default)]
-//     pub treat_as_flag: bool,
-// }
+    // A table of arguments, read and parsed from the environment command line.
+    // Binds double-hyphenated argument names (--arg) to a value, with additional metadata
+    // available to produce help messages, set default values, and apply other behavior.
+    #[serde(default)]
+    pub args: BTreeMap<String, PatchArgs>,
+}
+
+// A single patch after loading, along with the patch file metadata it inherits.
+#[derive(Debug)]
+pub struct LoadedPatch {
+    pub patch: Patch,
+    pub priority: Priority,
+    // Path of the patch file this patch was declared in, relative to the mod directory.
+    pub path: PathBuf,
+    pub strict: bool,
+    // Set by `unified` in the manifest.
+    pub unified: bool,
+    pub game_version: Option<String>,
+    // Id of the mod this patch belongs to, or the mod's directory / zip name if it has none.
+    pub mod_id: String,
+    // Built-in vars of the patch file, shared by all of its patches.
+    pub builtins: Arc<Builtins>,
+}
+
+impl Patch {
+    /// The target of this patch. Module patches are not bound to a target.
+    pub fn target(&self) -> Option<&Target> {
+        match self {
+            Patch::Pattern(x) => Some(&x.target),
+            Patch::Regex(x) => Some(&x.target),
+            Patch::Function(x) => Some(&x.target),
+            Patch::Range(x) => Some(&x.target),
+            Patch::Copy(x) => Some(&x.target),
+            Patch::Module(_) => None,
+        }
+    }
+
+    /// The `if` block of this patch, if it has one.
+    pub fn condition(&self) -> Option<&Condition> {
+        match self {
+            Patch::Module(x) => x.condition.as_ref(),
+            _ => self.meta()?.condition.as_ref(),
+        }
+    }
+
+    /// How many times this patch is expected to match, if the patch file says so.
+    pub fn times(&self) -> Option<usize> {
+        match self {
+            Patch::Pattern(x) => x.times,
+            Patch::Regex(x) => x.times,
+            Patch::Function(x) => x.times,
+            Patch::Range(x) => x.times,
+            Patch::Copy(_) | Patch::Module(_) => None,
+        }
+    }
+
+    /// The type of this patch, as recorded in dumps and the patch report.
+    pub fn debug_type(&self) -> DebugPatchType {
+        match self {
+            Patch::Pattern(_) => DebugPatchType::Pattern,
+            Patch::Regex(_) => DebugPatchType::Regex,
+            Patch::Function(_) => DebugPatchType::Function,
+            Patch::Range(_) => DebugPatchType::Range,
+            Patch::Copy(_) => DebugPatchType::Copy,
+            Patch::Module(_) => DebugPatchType::Module,
+        }
+    }
+
+    /// The pattern, function name or range this patch is anchored to, for display.
+    pub fn anchor(&self) -> Option<String> {
+        match self {
+            Patch::Pattern(x) => Some(x.pattern.clone()),
+            Patch::Regex(x) => Some(x.pattern.clone()),
+            Patch::Function(x) => Some(x.function.clone()),
+            Patch::Range(x) => Some(x.describe()),
+            Patch::Copy(_) | Patch::Module(_) => None,
+        }
+    }
+
+    /// Apply this patch onto the rope, see the `apply` of each patch type. Module patches are
+    /// not applied to buffers and return `None`, see `PatchTable::apply_module_patches`.
+    pub fn apply(&self, target: &str, rope: &mut Rope, path: &Path) -> Option<ByteDebugEntry> {
+        match self {
+            Patch::Copy(x) => x.apply(target, rope, path),
+            Patch::Pattern(x) => x.apply(target, rope, path),
+            Patch::Regex(x) => x.apply(target, rope, path),
+            Patch::Function(x) => x.apply(target, rope, path),
+            Patch::Range(x) => x.apply(target, rope, path),
+            Patch::Module(_) => None,
+        }
+    }
+
+    /// The name of this patch type, as used in patch files.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Patch::Pattern(_) => "pattern",
+            Patch::Regex(_) => "regex",
+            Patch::Function(_) => "function",
+            Patch::Range(_) => "range",
+            Patch::Copy(_) => "copy",
+            Patch::Module(_) => "module",
+        }
+    }
+
+    /// The fields shared by every patch type bound to a target. `None` for module patches.
+    pub fn meta(&self) -> Option<&PatchMeta> {
+        match self {
+            Patch::Pattern(x) => Some(&x.meta),
+            Patch::Regex(x) => Some(&x.meta),
+            Patch::Function(x) => Some(&x.meta),
+            Patch::Range(x) => Some(&x.meta),
+            Patch::Copy(x) => Some(&x.meta),
+            Patch::Module(_) => None,
+        }
+    }
+}
+
+// Fields shared by the copy, pattern, regex, function and range patches, flattened into each.
+// Module patches give `name` and `before` another meaning, so they declare their own `if`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PatchMeta {
+    // Identifies this patch in the `after` and `before` constraints of other patches.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    // Apply this patch after / before the named patches, regardless of priority. Names of
+    // patches from other mods are prefixed with the mod id, e.g. `other-mod:patch-name`.
+    #[serde(default)]
+    pub after: Vec<String>,
+    #[serde(default)]
+    pub before: Vec<String>,
+
+    // Only load this patch when every condition holds, see `Condition`.
+    #[serde(default, rename = "if")]
+    pub condition: Option<Condition>,
+
+    // Keys which no field of the patch claims. Flattened fields bypass the unknown key warning
+    // of the patch file parser, so they are collected here and warned about separately.
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
@@ -76,15 +237,150 @@ pub enum Patch {
     // to the provided pattern has been found.
     Pattern(PatternPatch),
     Regex(RegexPatch),
+    // A patch which inserts into or wraps the body of a named Lua function.
+    Function(FunctionPatch),
+    // A patch which replaces, deletes or wraps the lines between two patterns.
+    Range(RangePatch),
     Copy(CopyPatch),
     Module(ModulePatch),
 }
 
+// Names containing `*` or `?` are globs, where `*` also matches across directories.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum Target {
-    Single(String),
-    Multi(Vec<String>),
+    Single(TargetName),
+    Multi(Vec<TargetName>),
+    // Written as `target = { regex = "..." }`, matched against the whole target name.
+    Regex { regex: TargetRegex },
+}
+
+// An exact target name or a glob. Globs are compiled once when the patch file is parsed.
+#[derive(Debug, Clone)]
+pub struct TargetName {
+    name: String,
+    glob: Option<WildMatch>,
+}
+
+impl TargetName {
+    pub fn new(name: &str) -> Self {
+        TargetName {
+            name: name.to_string(),
+            glob: is_glob(name).then(|| WildMatch::new(name)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_glob(&self) -> bool {
+        self.glob.is_some()
+    }
+
+    pub fn matches(&self, target: &str) -> bool {
+        match &self.glob {
+            Some(glob) => glob.matches(target),
+            None => self.name == target,
+        }
+    }
+}
+
+impl From<&str> for TargetName {
+    fn from(name: &str) -> Self {
+        TargetName::new(name)
+    }
+}
+
+impl PartialEq<str> for TargetName {
+    fn eq(&self, other: &str) -> bool {
+        self.name == other
+    }
+}
+
+impl Serialize for TargetName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(TargetName::new(&String::deserialize(deserializer)?))
+    }
+}
+
+// A regex target, compiled once when the patch file is parsed.
+#[derive(Debug, Clone)]
+pub struct TargetRegex {
+    pattern: String,
+    regex: Regex,
+}
+
+impl TargetRegex {
+    pub fn new(pattern: &str) -> Result<Self, regex_lite::Error> {
+        // Anchor the regex so that it has to match the whole name, like exact and glob targets.
+        let regex = Regex::new(&format!("^(?:{pattern})$"))?;
+        Ok(TargetRegex {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, target: &str) -> bool {
+        self.regex.is_match(target)
+    }
+}
+
+impl Serialize for TargetRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        TargetRegex::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Check if the target name is a glob rather than an exact name.
+pub fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// The targets of every patch in a patch table. Exact names are looked up in a set, globs and
+/// regexes are only tried when that lookup misses.
+#[derive(Debug, Default)]
+pub struct TargetSet {
+    exact: HashSet<String>,
+    globs: Vec<WildMatch>,
+    regexes: Vec<TargetRegex>,
+}
+
+impl TargetSet {
+    pub fn insert(&mut self, name: &str) {
+        if is_glob(name) {
+            self.globs.push(WildMatch::new(name));
+        } else {
+            self.exact.insert(name.to_string());
+        }
+    }
+
+    pub fn insert_regex(&mut self, regex: &TargetRegex) {
+        self.regexes.push(regex.clone());
+    }
+
+    pub fn contains(&self, target: &str) -> bool {
+        self.exact.contains(target)
+            || self.globs.iter().any(|x| x.matches(target))
+            || self.regexes.iter().any(|x| x.is_match(target))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
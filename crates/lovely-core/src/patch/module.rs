@@ -2,12 +2,15 @@ use std::{
     ffi::CString,
     path::{Path, PathBuf},
     ptr,
+    sync::Arc,
 };
 
 use crate::sys::{self, lua_identity_closure, lua_err_identity_closure, LuaState, LuaStateTrait};
 use crate::RUNTIME;
 use serde::{Deserialize, Serialize};
 
+use super::Condition;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ModulePatch {
     pub source: PathBuf,
@@ -21,13 +24,17 @@ pub struct ModulePatch {
     #[serde(default)]
     pub load_now: bool,
 
+    // Only load this patch when every condition holds, see `Condition`.
+    #[serde(default, rename = "if")]
+    pub condition: Option<Condition>,
+
     // Used for display name of the source. Is the relative path.
     #[serde(skip)]
     pub display_source: String,
 
-    // Preloaded at load time
+    // Read at load time, shared with every other patch using the same source.
     #[serde(skip)]
-    pub content: String,
+    pub content: Arc<str>,
 }
 
 impl ModulePatch {
@@ -7,7 +7,7 @@ use wildmatch::WildMatch;
 
 use crate::dump::{ByteDebugEntry, ByteRegion, PatchSource, DebugPatchType};
 
-use super::{InsertPosition, Target};
+use super::{suggest, InsertPosition, PatchMeta, Target};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PatternPatch {
@@ -30,11 +30,20 @@ pub struct PatternPatch {
     #[serde(default)]
     pub overwrite: bool,
 
-    // Currently unused.
-    pub name: Option<String>,
+    #[serde(flatten)]
+    pub meta: PatchMeta,
+
+    // One matcher per line of the pattern, compiled at load time.
+    #[serde(skip)]
+    pub matchers: Vec<WildMatch>,
 }
 
 impl PatternPatch {
+    /// Compile the pattern into per-line matchers, so that they are reused for every target.
+    pub fn compile(&mut self) {
+        self.matchers = self.pattern.lines().map(|x| WildMatch::new(x.trim())).collect();
+    }
+
     pub fn debug_from_warning_string(&self, path: &Path, warning: String) -> ByteDebugEntry {
         log::warn!("{}", warning);
         ByteDebugEntry {
@@ -45,6 +54,7 @@ impl PatternPatch {
             },
             regions: Vec::new(),
             warnings: Some(vec![warning.to_string()]),
+            matches: Some(0),
         }
     }
 
@@ -55,12 +65,14 @@ impl PatternPatch {
             return None;
         }
 
-        let wm_lines = self
-            .pattern
-            .lines()
-            .map(|x| x.trim())
-            .map(WildMatch::new)
-            .collect_vec();
+        // Patches which were not loaded through the patch table may not be compiled yet.
+        let compiled;
+        let wm_lines = if self.matchers.is_empty() {
+            compiled = self.pattern.lines().map(|x| WildMatch::new(x.trim())).collect_vec();
+            &compiled
+        } else {
+            &self.matchers
+        };
         if wm_lines.is_empty() {
             return Some(self.debug_from_warning_string(path, format!(
                 "Pattern on target '{target}' for pattern patch from {} has no lines",
@@ -70,7 +82,9 @@ impl PatternPatch {
         let wm_lines_len = wm_lines.len();
 
         let mut line_index = 0usize;
-        let rope_lines = rope.raw_lines().map(|x| x.to_string()).collect_vec();
+        // Borrow lines out of a single copy of the buffer rather than allocating one per line.
+        let buffer = rope.to_string();
+        let rope_lines = buffer.split_inclusive('\n').collect_vec();
         let mut matches = Vec::new();
         while let Option::Some(rope_window) = rope_lines.get(line_index..line_index + wm_lines_len)
         {
@@ -98,12 +112,16 @@ impl PatternPatch {
         }
 
         if matches.is_empty() {
+            // Wildcards would only count against otherwise close lines.
+            let candidates = suggest::closest_lines(&buffer, &self.pattern.replace('*', ""));
             return Some(self.debug_from_warning_string(path, format!(
-                "Pattern '{}' on target '{target}' for pattern patch from {} resulted in no matches",
+                "Pattern '{}' on target '{target}' for pattern patch from {} resulted in no matches{}",
                 self.pattern.escape_debug(),
                 path.display(),
+                suggest::format_suggestions(target, &candidates),
             )));
         }
+        let found_matches = matches.len();
         let mut warnings = Vec::new();
         if let Some(times) = self.times {
             fn warn_pattern_mismatch(
@@ -185,6 +203,7 @@ impl PatternPatch {
             },
             regions: byte_regions,
             warnings: if warnings.is_empty() {None} else {Some(warnings)},
+            matches: Some(found_matches),
         })
     }
 }
@@ -0,0 +1,368 @@
+use std::path::Path;
+
+use crop::Rope;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use wildmatch::WildMatch;
+
+use crate::dump::{ByteDebugEntry, ByteRegion, DebugPatchType, PatchSource};
+
+use super::{suggest, PatchMeta, Target};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum RangeAction {
+    // Replace the range with the payload.
+    Replace,
+    // Remove the range.
+    Delete,
+    // Insert the payload before the range and `closing` after it.
+    Wrap,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RangePatch {
+    pub target: Target,
+
+    // Patterns for the first and last lines of the range, with the same wildcard semantics as
+    // pattern patches. The end of a range is the first match of `end` after its start.
+    pub start: String,
+    pub end: String,
+
+    // Only accept an end whose first line is indented exactly like the start, such as the
+    // `end` which closes a function rather than an `if` inside of it.
+    #[serde(default)]
+    pub same_indent: bool,
+
+    // Include the lines matched by `start` and `end` in the range. By default only the lines
+    // between them are.
+    #[serde(default)]
+    pub inclusive: bool,
+
+    pub action: RangeAction,
+    #[serde(default)]
+    pub payload: String,
+    // Inserted after the range by `action = "wrap"`.
+    #[serde(default)]
+    pub closing: String,
+    // Indent the payload and closing like the start line.
+    #[serde(default)]
+    pub match_indent: bool,
+
+    // Apply patch at most `times` times, warn if the number of ranges differs from `times`.
+    pub times: Option<usize>,
+
+    #[serde(flatten)]
+    pub meta: PatchMeta,
+
+    // Per-line matchers of `start` and `end`, compiled at load time.
+    #[serde(skip)]
+    pub start_matchers: Vec<WildMatch>,
+    #[serde(skip)]
+    pub end_matchers: Vec<WildMatch>,
+}
+
+fn matchers(pattern: &str) -> Vec<WildMatch> {
+    pattern.lines().map(|x| WildMatch::new(x.trim())).collect()
+}
+
+fn indent_of(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Whether the window of lines starting at `at` matches every matcher.
+fn matches_at(lines: &[&str], at: usize, matchers: &[WildMatch]) -> bool {
+    lines.get(at..at + matchers.len()).is_some_and(|window| {
+        window
+            .iter()
+            .zip(matchers)
+            .all(|(line, matcher)| matcher.matches(line.trim()))
+    })
+}
+
+impl RangePatch {
+    /// Compile `start` and `end` into per-line matchers, so that they are reused for every target.
+    pub fn compile(&mut self) {
+        self.start_matchers = matchers(&self.start);
+        self.end_matchers = matchers(&self.end);
+    }
+
+    /// The start and end patterns, for display.
+    pub fn describe(&self) -> String {
+        format!("{} ... {}", self.start, self.end)
+    }
+
+    fn source(&self, path: &Path) -> PatchSource {
+        PatchSource {
+            file: path.display().to_string(),
+            pattern: Some(self.describe()),
+            patch_type: DebugPatchType::Range,
+        }
+    }
+
+    /// Indent every line of the text like `indent`, and terminate it with a newline. Empty text
+    /// stays empty, so that an empty payload removes the range without leaving a blank line.
+    fn indented(&self, text: &str, indent: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        let indent = if self.match_indent { indent } else { "" };
+        let mut out = text
+            .split_inclusive('\n')
+            .format_with("", |x, f| f(&format_args!("{indent}{x}")))
+            .to_string();
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Apply the range patch onto the rope.
+    /// Returns `Some(ByteDebugEntry)` if the patch targets this buffer, `None` otherwise.
+    pub fn apply(&self, target: &str, rope: &mut Rope, path: &Path) -> Option<ByteDebugEntry> {
+        if !self.target.can_apply(target) {
+            return None;
+        }
+
+        // Patches which were not loaded through the patch table may not be compiled yet.
+        let (compiled_start, compiled_end);
+        let (start_matchers, end_matchers) = if self.start_matchers.is_empty() {
+            compiled_start = matchers(&self.start);
+            compiled_end = matchers(&self.end);
+            (&compiled_start, &compiled_end)
+        } else {
+            (&self.start_matchers, &self.end_matchers)
+        };
+        if start_matchers.is_empty() || end_matchers.is_empty() {
+            return Some(ByteDebugEntry::from_warning(self.source(path), format!(
+                "Start or end of range on target '{target}' for range patch from {} has no lines",
+                path.display()
+            )));
+        }
+
+        let buffer = rope.to_string();
+        let lines = buffer.split_inclusive('\n').collect_vec();
+        let line_starts = lines
+            .iter()
+            .scan(0, |offset, x| {
+                let start = *offset;
+                *offset += x.len();
+                Some(start)
+            })
+            .chain([buffer.len()])
+            .collect_vec();
+
+        // Find every range as (first line of start, first line of end), without overlaps.
+        let mut ranges = Vec::new();
+        let mut warnings = Vec::new();
+        let mut line = 0;
+        while line < lines.len() {
+            if !matches_at(&lines, line, start_matchers) {
+                line += 1;
+                continue;
+            }
+
+            let indent = indent_of(lines[line]);
+            let end = (line + start_matchers.len()..lines.len()).find(|&x| {
+                matches_at(&lines, x, end_matchers) && (!self.same_indent || indent_of(lines[x]) == indent)
+            });
+            let Some(end) = end else {
+                let warning = format!(
+                    "Range starting at line {} of target '{target}' for range patch from {} has no end matching '{}'",
+                    line + 1,
+                    path.display(),
+                    self.end.escape_debug()
+                );
+                warnings.push(warning);
+                break;
+            };
+
+            ranges.push((line, end));
+            line = end + end_matchers.len();
+        }
+
+        if ranges.is_empty() {
+            let candidates = suggest::closest_lines(&buffer, &self.start.replace('*', ""));
+            let mut warning = format!(
+                "Range '{}' on target '{target}' for range patch from {} resulted in no matches{}",
+                self.describe().escape_debug(),
+                path.display(),
+                suggest::format_suggestions(target, &candidates)
+            );
+            for x in warnings {
+                warning = format!("{x}\n{warning}");
+            }
+            return Some(ByteDebugEntry::from_warning(self.source(path), warning));
+        }
+        for warning in &warnings {
+            log::warn!("{warning}");
+        }
+
+        let found_matches = ranges.len();
+        if let Some(times) = self.times {
+            if found_matches != times {
+                let warning = format!(
+                    "Range '{}' on target '{target}' for range patch from {} resulted in {found_matches} matches, wanted {times}",
+                    self.describe().escape_debug(),
+                    path.display()
+                );
+                log::warn!("{warning}");
+                warnings.push(warning);
+            }
+            if found_matches > times {
+                log::warn!("Ignoring excess matches");
+                warnings.push("Ignoring excess matches".to_string());
+                ranges.truncate(times);
+            }
+        }
+
+        // Ranges are in source order, so a running offset keeps positions valid as they're edited.
+        let mut delta: isize = 0;
+        let mut byte_regions = Vec::new();
+        for (start, end) in ranges {
+            let indent = indent_of(lines[start]);
+            let (first, last) = if self.inclusive {
+                (start, end + end_matchers.len())
+            } else {
+                (start + start_matchers.len(), end)
+            };
+            let range_start = line_starts[first].saturating_add_signed(delta);
+            let range_end = line_starts[last].saturating_add_signed(delta);
+
+            match self.action {
+                RangeAction::Replace | RangeAction::Delete => {
+                    let payload = if matches!(self.action, RangeAction::Replace) {
+                        self.indented(&self.payload, indent)
+                    } else {
+                        String::new()
+                    };
+                    let removed = range_end - range_start;
+                    rope.delete(range_start..range_end);
+                    rope.insert(range_start, &payload);
+                    let region_delta = payload.len() as isize - removed as isize;
+                    byte_regions.push(ByteRegion {
+                        start: range_start,
+                        end: range_start + payload.len(),
+                        delta: region_delta,
+                    });
+                    delta += region_delta;
+                }
+                RangeAction::Wrap => {
+                    let opening = self.indented(&self.payload, indent);
+                    let closing = self.indented(&self.closing, indent);
+
+                    rope.insert(range_start, &opening);
+                    byte_regions.push(ByteRegion {
+                        start: range_start,
+                        end: range_start + opening.len(),
+                        delta: opening.len() as isize,
+                    });
+
+                    let closing_start = range_end + opening.len();
+                    rope.insert(closing_start, &closing);
+                    byte_regions.push(ByteRegion {
+                        start: closing_start,
+                        end: closing_start + closing.len(),
+                        delta: closing.len() as isize,
+                    });
+                    delta += (opening.len() + closing.len()) as isize;
+                }
+            }
+        }
+
+        Some(ByteDebugEntry {
+            patch_source: self.source(path),
+            regions: byte_regions,
+            warnings: if warnings.is_empty() { None } else { Some(warnings) },
+            matches: Some(found_matches),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const SOURCE: &str = "function Card:update(dt)\n\
+        \x20   if self.frozen then\n\
+        \x20       return\n\
+        \x20   end\n\
+        \x20   self:tick(dt)\n\
+        end\n\
+        print('done')\n";
+
+    fn patch(action: &str, inclusive: bool) -> RangePatch {
+        toml::from_str(&format!(
+            r#"
+target = "card.lua"
+start = "function Card:update(dt)"
+end = "end"
+same_indent = true
+inclusive = {inclusive}
+action = "{action}"
+payload = "    self:tock(dt)"
+"#
+        ))
+        .unwrap()
+    }
+
+    fn apply(patch: &RangePatch) -> (String, ByteDebugEntry) {
+        let mut rope = Rope::from(SOURCE);
+        let entry = patch
+            .apply("card.lua", &mut rope, &PathBuf::from("mod/lovely.toml"))
+            .unwrap();
+        (rope.to_string(), entry)
+    }
+
+    #[test]
+    fn replaces_function_body() {
+        let (patched, entry) = apply(&patch("replace", false));
+        assert_eq!(
+            patched,
+            "function Card:update(dt)\n    self:tock(dt)\nend\nprint('done')\n"
+        );
+        assert_eq!(entry.matches, Some(1));
+    }
+
+    #[test]
+    fn empty_payload_replaces_with_nothing() {
+        let mut empty = patch("replace", false);
+        empty.payload = String::new();
+        let (patched, entry) = apply(&empty);
+
+        assert_eq!(patched, "function Card:update(dt)\nend\nprint('done')\n");
+        assert_eq!(entry.regions[0].start, entry.regions[0].end);
+    }
+
+    #[test]
+    fn deletes_inclusive_range() {
+        let (patched, entry) = apply(&patch("delete", true));
+        assert_eq!(patched, "print('done')\n");
+        assert_eq!(entry.regions[0].removed(), SOURCE.len() - "print('done')\n".len());
+    }
+
+    #[test]
+    fn wraps_range() {
+        let mut wrap = patch("wrap", false);
+        wrap.payload = "if not self.paused then".to_string();
+        wrap.closing = "end".to_string();
+        wrap.match_indent = true;
+        let (patched, entry) = apply(&wrap);
+
+        assert!(patched.starts_with("function Card:update(dt)\nif not self.paused then\n    if"));
+        assert!(patched.ends_with("    self:tick(dt)\nend\nend\nprint('done')\n"));
+        assert_eq!(entry.regions.len(), 2);
+    }
+
+    #[test]
+    fn missing_end_warns() {
+        let mut unclosed = patch("delete", true);
+        unclosed.end = "return nil".to_string();
+        let (patched, entry) = apply(&unclosed);
+
+        assert_eq!(patched, SOURCE);
+        assert_eq!(entry.matches, Some(0));
+        assert!(entry.warnings.unwrap()[0].contains("has no end matching 'return nil'"));
+    }
+}
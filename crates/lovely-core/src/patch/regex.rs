@@ -1,3 +1,4 @@
+use std::fmt;
 use std::path::Path;
 
 use regex_cursor::engines::meta::Regex;
@@ -12,7 +13,7 @@ use serde::{Deserialize, Serialize};
 use crate::chunk_vec_cursor::IntoCursor;
 use crate::dump::{ByteDebugEntry, ByteRegion, PatchSource, DebugPatchType};
 
-use super::{InsertPosition, Target};
+use super::{suggest, InsertPosition, PatchMeta, Target};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegexPatch {
@@ -44,11 +45,41 @@ pub struct RegexPatch {
     #[serde(default)]
     pub verbose: bool,
 
-    // Currently unused.
-    pub name: Option<String>,
+    #[serde(flatten)]
+    pub meta: PatchMeta,
+
+    // Compiled at load time.
+    #[serde(skip)]
+    pub regex: Option<CompiledRegex>,
+}
+
+pub struct CompiledRegex(Regex);
+
+impl fmt::Debug for CompiledRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompiledRegex")
+    }
 }
 
 impl RegexPatch {
+    fn build(&self) -> Result<Regex, String> {
+        Regex::builder()
+            .syntax(
+                syntax::Config::new()
+                    .multi_line(true)
+                    .crlf(true)
+                    .ignore_whitespace(self.verbose),
+            )
+            .build(&self.pattern)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Compile the regex, so that it is reused for every target.
+    pub fn compile(&mut self) -> Result<(), String> {
+        self.regex = Some(CompiledRegex(self.build()?));
+        Ok(())
+    }
+
     pub fn debug_from_warning_string(&self, path: &Path, warning: String) -> ByteDebugEntry {
         log::warn!("{}", warning);
         ByteDebugEntry {
@@ -59,6 +90,7 @@ impl RegexPatch {
             },
             regions: Vec::new(),
             warnings: Some(vec![warning.to_string()]),
+            matches: Some(0),
         }
     }
 
@@ -67,28 +99,42 @@ impl RegexPatch {
             return None;
         }
 
+        // Patches which were not loaded through the patch table may not be compiled yet.
+        let compiled;
+        let re = match &self.regex {
+            Some(x) => &x.0,
+            None => match self.build() {
+                Ok(x) => {
+                    compiled = x;
+                    &compiled
+                }
+                Err(e) => {
+                    let warning = format!(
+                        "Failed to compile regex '{}' for regex patch from {}: {e}",
+                        self.pattern.escape_debug(),
+                        path.display()
+                    );
+                    return Some(self.debug_from_warning_string(path, warning));
+                }
+            },
+        };
+
         let input = Input::new(rope.into_cursor());
-        let re = Regex::builder()
-            .syntax(
-                syntax::Config::new()
-                    .multi_line(true)
-                    .crlf(true)
-                    .ignore_whitespace(self.verbose),
-            )
-            .build(&self.pattern)
-            .unwrap_or_else(|e| {
-                panic!(
-                    "Failed to compile Regex '{}' for regex patch from {}: {e:?}",
-                    path.display(),
-                    self.pattern
-                )
-            });
 
         let mut captures = re.captures_iter(input).collect_vec();
         if captures.is_empty() {
-            let warning = format!("Regex '{}' on target '{target}' for regex patch from {} resulted in no matches", self.pattern.escape_debug(), path.display());
+            // Compare against the pattern without escapes, which is close enough to the source
+            // for most patterns.
+            let candidates = suggest::closest_lines(&rope.to_string(), &self.pattern.replace('\\', ""));
+            let warning = format!(
+                "Regex '{}' on target '{target}' for regex patch from {} resulted in no matches{}",
+                self.pattern.escape_debug(),
+                path.display(),
+                suggest::format_suggestions(target, &candidates)
+            );
             return Some(self.debug_from_warning_string(path, warning));
         }
+        let found_matches = captures.len();
         let mut warnings = Vec::new();
         if let Some(times) = self.times {
             fn warn_regex_mismatch(pattern: &str, target: &str, found_matches: usize, wanted_matches: usize, path: &Path) -> String {
@@ -262,6 +308,8 @@ impl RegexPatch {
                 patch_type: DebugPatchType::Regex,
             },
             regions: byte_regions,
-            warnings: if warnings.is_empty() {None} else {Some(warnings)}, })
+            warnings: if warnings.is_empty() {None} else {Some(warnings)},
+            matches: Some(found_matches),
+        })
     }
 }
@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use semver::{Version, VersionReq};
+
+use crate::patch::{Manifest, Priority};
+
+/// Identity and relationships of a single mod (directory or zip), aggregated from the
+/// manifests of all of its patch files.
+#[derive(Debug)]
+pub struct ModInfo {
+    // Display name, the directory or zip file name.
+    pub name: String,
+    // The mod's directory or zip file.
+    pub path: PathBuf,
+    pub id: Option<String>,
+    // `None` if the mod has no id, or if its version is not valid semver.
+    pub version: Option<Version>,
+    pub dependencies: BTreeMap<String, VersionReq>,
+    pub conflicts: BTreeMap<String, VersionReq>,
+    pub provides: Vec<String>,
+    // Lovely version ranges required by the mod's patch files, and the files requiring them.
+    pub lovely_versions: Vec<(VersionReq, PathBuf)>,
+    // The highest priority of any of the mod's patch files.
+    pub priority: Priority,
+}
+
+impl ModInfo {
+    pub fn new(base_path: &Path) -> Self {
+        let name = base_path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        ModInfo {
+            name,
+            path: base_path.to_path_buf(),
+            id: None,
+            version: None,
+            dependencies: BTreeMap::new(),
+            conflicts: BTreeMap::new(),
+            provides: Vec::new(),
+            lovely_versions: Vec::new(),
+            priority: Priority::MIN,
+        }
+    }
+
+    /// Merge the manifest of one of this mod's patch files into the mod info.
+    pub fn merge_manifest(&mut self, manifest: &Manifest, file: &Path) {
+        if let Some(id) = &manifest.id {
+            match &self.id {
+                // `:` separates the mod id from the patch name in `after` and `before`.
+                _ if id.contains(':') => {
+                    warn!(
+                        "Patch file {file:?} declares mod id '{id}', which contains ':', \
+                        ignoring it"
+                    );
+                }
+                None => {
+                    self.id = Some(id.clone());
+                    self.version = Version::parse(&manifest.version).ok();
+                    if self.version.is_none() {
+                        warn!(
+                            "Version '{}' of mod '{id}' is not a valid semantic version, \
+                            only dependencies on any version of it can be satisfied",
+                            manifest.version
+                        );
+                    }
+                }
+                Some(existing) if existing != id => {
+                    warn!(
+                        "Patch file {file:?} declares mod id '{id}', but '{}' was already \
+                        identified as '{existing}', ignoring it",
+                        self.name
+                    );
+                }
+                Some(_) => (),
+            }
+        }
+
+        self.dependencies.extend(manifest.dependencies.clone());
+        self.conflicts.extend(manifest.conflicts.clone());
+        self.provides.extend(manifest.provides.iter().cloned());
+        if let Some(req) = &manifest.lovely_version {
+            self.lovely_versions.push((req.clone(), file.to_path_buf()));
+        }
+        self.priority = self.priority.max(manifest.priority);
+    }
+
+    /// A human readable name of the mod for log messages.
+    fn display(&self) -> String {
+        match &self.id {
+            Some(id) => format!("'{id}' ({})", self.name),
+            None => format!("'{}'", self.name),
+        }
+    }
+}
+
+/// Check if the installed version of a mod satisfies the requirement. A version is only needed
+/// when the requirement is more specific than `*`.
+fn version_matches(req: &VersionReq, version: Option<&Version>) -> bool {
+    *req == VersionReq::STAR || version.is_some_and(|x| req.matches(x))
+}
+
+/// Map every id (including provided ids) of the enabled mods to the index of the mod which
+/// declares it. The first mod to declare an id wins.
+fn available_ids<'a>(mods: &'a [ModInfo], enabled: &[bool]) -> HashMap<&'a str, usize> {
+    let mut available = HashMap::new();
+    for (i, m) in mods.iter().enumerate().filter(|(i, _)| enabled[*i]) {
+        for id in m.id.iter().chain(m.provides.iter()) {
+            available.entry(id.as_str()).or_insert(i);
+        }
+    }
+    available
+}
+
+/// Resolve mod dependencies and conflicts.
+///
+/// Mods with a patch file which does not support the running Lovely version are skipped as a
+/// whole, as are mods with unmet dependencies. Conflicts are reported, and the remaining mods are
+/// ordered so that every mod comes after its dependencies. Mods without a dependency
+/// relationship keep their original order.
+///
+/// Returns the index of each mod to load, in load order, along with the minimum priority its
+/// patch files must have to be applied after the patches of its dependencies.
+pub fn resolve_mods(mods: &[ModInfo], lovely_version: &Version) -> Vec<(usize, Priority)> {
+    let mut enabled = vec![true; mods.len()];
+
+    for (i, m) in mods.iter().enumerate() {
+        let unsupported = m
+            .lovely_versions
+            .iter()
+            .find(|(req, _)| !req.matches(lovely_version));
+        if let Some((req, file)) = unsupported {
+            error!(
+                "Mod {} requires Lovely {req} (declared in {file:?}), but this is Lovely \
+                {lovely_version}. Skipping it, install a supported Lovely version to use it",
+                m.display()
+            );
+            enabled[i] = false;
+        }
+    }
+
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for (i, m) in mods.iter().enumerate() {
+        let Some(id) = &m.id else { continue };
+        if let Some(&first) = seen.get(id.as_str()) {
+            warn!(
+                "Both {} and {} declare the mod id '{id}', dependencies will resolve to {}",
+                mods[first].name,
+                m.name,
+                mods[first].name
+            );
+        } else {
+            seen.insert(id.as_str(), i);
+        }
+    }
+
+    // Disabling a mod can leave mods which depend on it unmet, so repeat until nothing changes.
+    loop {
+        let available = available_ids(mods, &enabled);
+        let mut changed = false;
+
+        for (i, m) in mods.iter().enumerate() {
+            if !enabled[i] {
+                continue;
+            }
+
+            for (dep, req) in &m.dependencies {
+                let reason = match available.get(dep.as_str()) {
+                    Some(&j) if version_matches(req, mods[j].version.as_ref()) => continue,
+                    Some(&j) => match &mods[j].version {
+                        Some(version) => format!("version {version} of it is installed"),
+                        None => "its installed version is not a valid semantic version".into(),
+                    },
+                    None => "it is not installed or was skipped".into(),
+                };
+
+                error!(
+                    "Mod {} requires '{dep}' {req}, but {reason}. Skipping it",
+                    m.display()
+                );
+                enabled[i] = false;
+                changed = true;
+                break;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let available = &available_ids(mods, &enabled);
+    let dependencies_of = move |i: usize| {
+        mods[i]
+            .dependencies
+            .keys()
+            .filter_map(move |dep| available.get(dep.as_str()).copied())
+            .filter(move |&j| j != i)
+    };
+
+    for (i, m) in mods.iter().enumerate().filter(|(i, _)| enabled[*i]) {
+        for (other, req) in &m.conflicts {
+            let Some(&j) = available.get(other.as_str()) else { continue };
+            if j != i && version_matches(req, mods[j].version.as_ref()) {
+                error!(
+                    "Mod {} conflicts with {} ('{other}' {req}), expect problems",
+                    m.display(),
+                    mods[j].display()
+                );
+            }
+        }
+    }
+
+    // Place the first mod (in original order) whose dependencies have all been placed. If none
+    // exists the remaining mods form a cycle, which we break by placing the first of them.
+    let enabled_count = enabled.iter().filter(|x| **x).count();
+    let mut placed = vec![false; mods.len()];
+    let mut order = Vec::with_capacity(enabled_count);
+    while order.len() < enabled_count {
+        let mut unplaced = (0..mods.len()).filter(|&i| enabled[i] && !placed[i]);
+        let next = unplaced
+            .clone()
+            .find(|&i| dependencies_of(i).all(|j| placed[j]))
+            .unwrap_or_else(|| {
+                let first = unplaced.next().unwrap();
+                warn!(
+                    "Mod {} is part of a dependency cycle, loading it before its dependencies",
+                    mods[first].display()
+                );
+                first
+            });
+
+        placed[next] = true;
+        order.push(next);
+    }
+
+    // Raise priorities so that dependents are never patched before their dependencies.
+    let mut effective: Vec<Priority> = mods.iter().map(|x| x.priority).collect();
+    order
+        .into_iter()
+        .map(|i| {
+            let floor = dependencies_of(i)
+                .map(|j| effective[j])
+                .max()
+                .unwrap_or(Priority::MIN);
+            effective[i] = effective[i].max(floor);
+            (i, floor)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn info(name: &str, id: &str, version: &str, deps: &[(&str, &str)]) -> ModInfo {
+        let mut info = ModInfo::new(&PathBuf::from(name));
+        info.id = Some(id.to_string());
+        info.version = Version::parse(version).ok();
+        info.priority = 0;
+        for (dep, req) in deps {
+            info.dependencies
+                .insert(dep.to_string(), VersionReq::parse(req).unwrap());
+        }
+        info
+    }
+
+    fn indices(resolved: &[(usize, Priority)]) -> Vec<usize> {
+        resolved.iter().map(|(i, _)| *i).collect()
+    }
+
+    fn resolve_mods(mods: &[ModInfo]) -> Vec<(usize, Priority)> {
+        super::resolve_mods(mods, &Version::new(0, 9, 0))
+    }
+
+    #[test]
+    fn dependencies_load_first() {
+        let mods = [
+            info("a", "a", "1.0.0", &[("b", ">=1.0.0")]),
+            info("b", "b", "1.2.0", &[]),
+            info("c", "c", "1.0.0", &[]),
+        ];
+
+        assert_eq!(indices(&resolve_mods(&mods)), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn unmet_dependencies_are_skipped_transitively() {
+        let mods = [
+            info("a", "a", "1.0.0", &[("b", "*")]),
+            info("b", "b", "1.0.0", &[("missing", "*")]),
+            info("c", "c", "1.0.0", &[]),
+        ];
+
+        assert_eq!(indices(&resolve_mods(&mods)), vec![2]);
+    }
+
+    #[test]
+    fn version_mismatch_is_unmet() {
+        let mods = [
+            info("a", "a", "1.0.0", &[("b", "^2")]),
+            info("b", "b", "1.5.0", &[]),
+        ];
+
+        assert_eq!(indices(&resolve_mods(&mods)), vec![1]);
+    }
+
+    #[test]
+    fn provided_ids_satisfy_dependencies() {
+        let mut provider = info("b", "b", "3.1.0", &[]);
+        provider.provides.push("api".to_string());
+        let mods = [info("a", "a", "1.0.0", &[("api", ">=3")]), provider];
+
+        assert_eq!(indices(&resolve_mods(&mods)), vec![1, 0]);
+    }
+
+    #[test]
+    fn dependents_inherit_priority_floor() {
+        let mut dep = info("b", "b", "1.0.0", &[]);
+        dep.priority = 10;
+        let mods = [info("a", "a", "1.0.0", &[("b", "*")]), dep];
+
+        assert_eq!(resolve_mods(&mods), vec![(1, Priority::MIN), (0, 10)]);
+    }
+
+    #[test]
+    fn cycles_fall_back_to_original_order() {
+        let mods = [
+            info("a", "a", "1.0.0", &[("b", "*")]),
+            info("b", "b", "1.0.0", &[("a", "*")]),
+        ];
+
+        assert_eq!(indices(&resolve_mods(&mods)), vec![0, 1]);
+    }
+
+    #[test]
+    fn ids_with_colons_are_ignored() {
+        let mut info = ModInfo::new(&PathBuf::from("a"));
+        let manifest: Manifest = toml::from_str("version = \"1.0.0\"\nid = \"a:b\"").unwrap();
+        info.merge_manifest(&manifest, Path::new("a/lovely.toml"));
+
+        assert_eq!(info.id, None);
+    }
+
+    #[test]
+    fn unsupported_lovely_version_is_skipped() {
+        let mut newer = info("a", "a", "1.0.0", &[]);
+        newer
+            .lovely_versions
+            .push((VersionReq::parse(">=0.10.0").unwrap(), PathBuf::from("a/lovely.toml")));
+        let mut supported = info("b", "b", "1.0.0", &[]);
+        supported
+            .lovely_versions
+            .push((VersionReq::parse(">=0.8, <0.10").unwrap(), PathBuf::from("b/lovely.toml")));
+        let dependent = info("c", "c", "1.0.0", &[("a", "*")]);
+
+        assert_eq!(indices(&resolve_mods(&[newer, supported, dependent])), vec![1]);
+    }
+}
@@ -0,0 +1,131 @@
+use itertools::Itertools;
+
+// At most this many candidates are suggested for a patch which found no matches.
+const MAX_SUGGESTIONS: usize = 3;
+// Suggestions are computed on every load of a target, so longer needles are not compared at all,
+// and the search stops once it has compared this many pairs of chars.
+const MAX_NEEDLE_CHARS: usize = 400;
+const MAX_COMPARISONS: usize = 20_000_000;
+
+/// Find the lines of `buffer` closest to `needle` by edit distance, comparing trimmed lines.
+/// Multi-line needles are compared against windows of as many lines. Candidates which differ in
+/// more than half of the needle are dropped. Returns 1-based line numbers and the untrimmed
+/// lines, closest first. Gives up early on long needles and large buffers, see `MAX_COMPARISONS`.
+pub fn closest_lines(buffer: &str, needle: &str) -> Vec<(usize, Vec<String>)> {
+    let needle = needle.lines().map(str::trim).join("\n");
+    let needle = needle.chars().collect_vec();
+    if needle.len() > MAX_NEEDLE_CHARS {
+        return Vec::new();
+    }
+    let window = needle.iter().filter(|&&x| x == '\n').count() + 1;
+    let max_distance = needle.len() / 2;
+
+    let lines = buffer.lines().collect_vec();
+    let mut candidates = Vec::new();
+    let mut budget = MAX_COMPARISONS;
+    for (start, group) in lines.windows(window).enumerate() {
+        let text = group.iter().map(|x| x.trim()).join("\n").chars().collect_vec();
+        if text.is_empty() || text.len().abs_diff(needle.len()) > max_distance {
+            continue;
+        }
+
+        let Some(left) = budget.checked_sub(needle.len() * text.len()) else {
+            break;
+        };
+        budget = left;
+        if let Some(distance) = edit_distance(&needle, &text, max_distance) {
+            candidates.push((distance, start + 1, group));
+        }
+    }
+
+    candidates
+        .into_iter()
+        .sorted_by_key(|(distance, line, _)| (*distance, *line))
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, line, group)| (line, group.iter().map(|x| x.to_string()).collect()))
+        .collect()
+}
+
+/// Format the candidates found by `closest_lines` to be appended to a warning.
+pub fn format_suggestions(target: &str, candidates: &[(usize, Vec<String>)]) -> String {
+    if candidates.is_empty() {
+        return String::new();
+    }
+
+    let mut out = format!("\nClosest lines in '{target}':");
+    for (line, group) in candidates {
+        let label = match group.len() {
+            1 => format!("line {line}: "),
+            n => format!("lines {line}-{}: ", line + n - 1),
+        };
+        let indent = " ".repeat(label.len() + 2);
+        out.push_str(&format!("\n  {label}{}", group[0].trim()));
+        for text in &group[1..] {
+            out.push_str(&format!("\n{indent}{}", text.trim()));
+        }
+    }
+    out
+}
+
+/// Levenshtein distance between two strings of chars, or `None` once it must exceed `max`.
+fn edit_distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    let mut prev = (0..=b.len()).collect_vec();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(x != y);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        // Distances never shrink from one row to the next.
+        if cur.iter().all(|&x| x > max) {
+            return None;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    Some(prev[b.len()]).filter(|&x| x <= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUFFER: &str = "local x = 1\n\
+        if G.STATE == G.STATES.SHOP then\n\
+        \x20   G.shop:update(dt)\n\
+        end\n";
+
+    #[test]
+    fn finds_renamed_line() {
+        let found = closest_lines(BUFFER, "if G.STATE == G.STATES.STORE then");
+        assert_eq!(found[0].0, 2);
+
+        let message = format_suggestions("game.lua", &found);
+        assert!(message.contains("line 2: if G.STATE == G.STATES.SHOP then"));
+    }
+
+    #[test]
+    fn compares_multi_line_windows() {
+        let found = closest_lines(BUFFER, "G.shop:update(dt)\nend");
+        assert_eq!(found[0], (3, vec!["    G.shop:update(dt)".to_string(), "end".to_string()]));
+        assert!(format_suggestions("game.lua", &found).contains("lines 3-4: G.shop:update(dt)"));
+    }
+
+    #[test]
+    fn ignores_unrelated_lines() {
+        assert!(closest_lines(BUFFER, "love.graphics.setColor(1, 1, 1)").is_empty());
+        assert_eq!(format_suggestions("game.lua", &[]), "");
+    }
+
+    #[test]
+    fn gives_up_on_large_inputs() {
+        let needle = "x".repeat(MAX_NEEDLE_CHARS + 1);
+        assert!(closest_lines(&needle, &needle).is_empty());
+
+        // Every line is as long as the needle, so the budget runs out long before the match.
+        let line = "y".repeat(MAX_NEEDLE_CHARS);
+        let needle = "x".repeat(MAX_NEEDLE_CHARS);
+        let buffer = format!("{}{needle}\n", format!("{line}\n").repeat(1000));
+        assert!(closest_lines(&buffer, &needle).is_empty());
+    }
+}
@@ -1,48 +1,379 @@
 use anyhow::Result;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
 
-use crate::dump::{ByteDebugEntry, PatchDebug};
-use crate::patch::{loader, vars};
-use crate::patch::{Patch, Priority};
+use crate::dump::{ByteConflict, ByteDebugEntry, ConflictKind, PatchDebug};
+use crate::lua_parser;
+use crate::patch::args::ArgTable;
+use crate::patch::loader::{self, LoadedMods};
+use crate::patch::vars::{self, ModVars};
+use crate::patch::{LoadedPatch, Patch, Target, TargetName, TargetSet};
+use crate::report::{PatchResult, PatchStatus};
+use crate::runtime_vars::RuntimeVars;
 use crate::sys::{preload_module, LuaFunc, LuaState, LuaTable};
 use crop::Rope;
 use itertools::Itertools;
 use log::*;
+use regex_lite::Regex;
+
+// Matches the line Lua prefixes syntax errors with, after the chunk name, e.g. `main.lua:12: `.
+static ERROR_LINE_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r":(\d+): ").unwrap());
 
 /// Structure to manage patch tables for Lovely runtime
 pub struct PatchTable {
     pub mod_dir: PathBuf,
-    pub targets: HashSet<String>,
-    // Unsorted
-    pub patches: Vec<(Patch, Priority, PathBuf)>,
-    pub vars: HashMap<String, String>,
-    // args: HashMap<String, String>,
+    pub targets: TargetSet,
+    // Unsorted, see `index` for the order patches are applied in.
+    pub patches: Vec<LoadedPatch>,
+    // Vars declared by each mod, interpolated into patched buffers.
+    pub vars: ModVars,
+    // Treat every pattern, regex, function and range patch as strict, regardless of its manifest.
+    pub strict: bool,
+    // Command line arguments declared by patch files, and their values.
+    pub args: ArgTable,
+    // Vars set through `lovely.set_var`, shared with the runtime. Those set with `interpolate`
+    // are interpolated after the vars declared by mods.
+    pub runtime_vars: Arc<RwLock<RuntimeVars>>,
+    index: PatchIndex,
 }
 
 impl Default for PatchTable {
     fn default() -> Self {
         Self {
             mod_dir: PathBuf::new(),
-            targets: HashSet::new(),
+            targets: TargetSet::default(),
             patches: Vec::new(),
-            vars: HashMap::new(),
+            vars: ModVars::default(),
+            strict: false,
+            args: ArgTable::default(),
+            runtime_vars: Default::default(),
+            index: PatchIndex::default(),
+        }
+    }
+}
+
+/// Patch indices grouped by target, built once at load time so that applying patches to a
+/// buffer only touches the patches aimed at it.
+#[derive(Debug, Default)]
+struct PatchIndex {
+    // Copy, pattern, regex, function and range patches by exact target name, in application order.
+    exact: HashMap<String, Vec<usize>>,
+    // Patches with glob or regex targets, which are matched against each buffer name.
+    dynamic: Vec<usize>,
+    // Copy, pattern, regex, function and range patches in application order.
+    order: Vec<usize>,
+    // The position of each patch in application order.
+    rank: Vec<usize>,
+    // `load_now` module patches by the target they are evaluated before, in priority order.
+    load_now: HashMap<String, Vec<usize>>,
+}
+
+impl PatchIndex {
+    fn build(patches: &[LoadedPatch]) -> Self {
+        let of_type = |matches: fn(&Patch) -> bool| {
+            patches
+                .iter()
+                .enumerate()
+                .filter(move |(_, x)| matches(&x.patch))
+                .map(|(i, _)| i)
+        };
+
+        // Copy patches run first, followed by pattern, regex, function and range patches, each
+        // sorted by priority, then by type, then by load order. Patches of unified files skip the
+        // first phase and the type, so they interleave by priority and declaration order.
+        let sort_key = |i: usize| {
+            let loaded = &patches[i];
+            let (phase, type_rank) = match &loaded.patch {
+                _ if loaded.unified => (1, 0),
+                Patch::Copy(_) => (0, 0),
+                Patch::Pattern(_) => (1, 0),
+                Patch::Regex(_) => (1, 1),
+                Patch::Function(_) => (1, 2),
+                _ => (1, 3),
+            };
+            (phase, loaded.priority, type_rank, i)
+        };
+        let default = of_type(|x| !matches!(x, Patch::Module(..)))
+            .sorted_by_key(|&i| sort_key(i))
+            .collect();
+
+        let order = order_by_constraints(patches, default);
+
+        let mut index = PatchIndex {
+            rank: vec![usize::MAX; patches.len()],
+            ..Default::default()
+        };
+        for (rank, &i) in order.iter().enumerate() {
+            index.rank[i] = rank;
+
+            let names: Vec<&TargetName> = match patches[i].patch.target() {
+                Some(Target::Single(x)) => vec![x],
+                Some(Target::Multi(xs)) => xs.iter().collect(),
+                Some(Target::Regex { .. }) | None => {
+                    index.dynamic.push(i);
+                    continue;
+                }
+            };
+
+            if names.iter().any(|x| x.is_glob()) {
+                index.dynamic.push(i);
+            }
+            for name in names.into_iter().filter(|x| !x.is_glob()) {
+                let entries = index.exact.entry(name.as_str().to_string()).or_default();
+                if entries.last() != Some(&i) {
+                    entries.push(i);
+                }
+            }
         }
+
+        let load_now = of_type(|x| matches!(x, Patch::Module(m) if m.load_now))
+            .sorted_by_key(|&i| patches[i].priority);
+        for i in load_now {
+            let Patch::Module(x) = &patches[i].patch else {
+                continue;
+            };
+            let before = x.before.clone().unwrap_or_default();
+            index.load_now.entry(before).or_default().push(i);
+        }
+
+        index.order = order;
+        index
     }
 }
 
+/// Reorder patches so that the `after` and `before` constraints between named patches hold,
+/// keeping the `default` order wherever the constraints allow. Constraints against patches which
+/// aren't loaded are ignored, and patches caught in a cycle fall back to the default order.
+fn order_by_constraints(patches: &[LoadedPatch], default: Vec<usize>) -> Vec<usize> {
+    // Names without a mod id refer to patches of the same mod.
+    let qualify = |mod_id: &str, name: &str| {
+        if name.contains(':') {
+            name.to_string()
+        } else {
+            format!("{mod_id}:{name}")
+        }
+    };
+
+    let mut by_name: HashMap<String, usize> = HashMap::new();
+    for (pos, &i) in default.iter().enumerate() {
+        let Some(name) = patches[i].patch.meta().and_then(|x| x.name.as_deref()) else {
+            continue;
+        };
+        let id = qualify(&patches[i].mod_id, name);
+        if let Some(&first) = by_name.get(&id) {
+            warn!(
+                "Patches from {} and {} are both named '{id}', constraints refer to the first one",
+                patches[default[first]].path.display(),
+                patches[i].path.display()
+            );
+            continue;
+        }
+        by_name.insert(id, pos);
+    }
+
+    // Edges between positions in the default order, from each patch to the patches which must
+    // be applied after it.
+    let mut successors = vec![Vec::new(); default.len()];
+    let mut blockers = vec![0; default.len()];
+    for (pos, &i) in default.iter().enumerate() {
+        let Some(meta) = patches[i].patch.meta() else {
+            continue;
+        };
+        let constraints = meta
+            .after
+            .iter()
+            .map(|x| (x, true))
+            .chain(meta.before.iter().map(|x| (x, false)));
+        for (name, is_after) in constraints {
+            let id = qualify(&patches[i].mod_id, name);
+            let Some(&other) = by_name.get(&id) else {
+                info!(
+                    "Patch from {} is ordered against '{id}', which is not loaded, ignoring it",
+                    patches[i].path.display()
+                );
+                continue;
+            };
+            let (first, second) = if is_after { (other, pos) } else { (pos, other) };
+            if first != second {
+                successors[first].push(second);
+                blockers[second] += 1;
+            }
+        }
+    }
+
+    // Kahn's algorithm, always picking the ready patch which comes first in the default order.
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..default.len())
+        .filter(|&pos| blockers[pos] == 0)
+        .map(Reverse)
+        .collect();
+    let mut placed = vec![false; default.len()];
+    let mut order = Vec::with_capacity(default.len());
+    while let Some(Reverse(pos)) = ready.pop() {
+        placed[pos] = true;
+        order.push(default[pos]);
+        for &next in &successors[pos] {
+            blockers[next] -= 1;
+            if blockers[next] == 0 {
+                ready.push(Reverse(next));
+            }
+        }
+    }
+
+    if order.len() < default.len() {
+        let stuck = (0..default.len()).filter(|&pos| !placed[pos]).collect_vec();
+        warn!(
+            "Ordering constraints of the patches from {} form a cycle, ignoring them",
+            stuck
+                .iter()
+                .map(|&pos| patches[default[pos]].path.display().to_string())
+                .unique()
+                .join(", ")
+        );
+        order.extend(stuck.into_iter().map(|pos| default[pos]));
+    }
+
+    order
+}
+
 impl PatchTable {
-    /// Load patches from the provided mod directory.
+    /// Load patches from the provided mod directory. Arguments take their defaults, and mods
+    /// are loaded even if they have required arguments.
     pub fn load(mod_dir: &Path) -> Result<PatchTable> {
-        let raw_patches = loader::load_patches_new(mod_dir)?;
-        let (patches, targets, vars) = loader::process_patches(raw_patches);
+        let mut raw_patches = loader::load_patches_new(mod_dir)?;
+        let args = ArgTable::parse(std::mem::take(&mut raw_patches.args), &[]);
+        Ok(Self::build(mod_dir, raw_patches, args))
+    }
 
-        Ok(PatchTable {
+    /// Load patches from the provided mod directory, parsing the arguments they declare from
+    /// the command line, excluding the executable. Mods missing a required argument are skipped.
+    pub fn load_with_args(mod_dir: &Path, cli_args: &[String]) -> Result<PatchTable> {
+        let mut raw_patches = loader::load_patches_new(mod_dir)?;
+        let args = ArgTable::parse(std::mem::take(&mut raw_patches.args), cli_args);
+        for (mod_id, error) in args.errors() {
+            warn!(
+                "{error}, skipping mod '{mod_id}'. Run the game with --help to list the \
+                arguments of every mod"
+            );
+            raw_patches.skip_mod(mod_id);
+        }
+        Ok(Self::build(mod_dir, raw_patches, args))
+    }
+
+    fn build(mod_dir: &Path, raw_patches: LoadedMods, args: ArgTable) -> PatchTable {
+        let (patches, targets, vars) = loader::process_patches(raw_patches, &args);
+        let index = PatchIndex::build(&patches);
+
+        PatchTable {
             mod_dir: mod_dir.to_path_buf(),
             targets,
             patches,
             vars,
-        })
+            strict: false,
+            args,
+            runtime_vars: Default::default(),
+            index,
+        }
+    }
+
+    /// Indices of the copy, pattern, regex, function and range patches aimed at the target, in
+    /// application order.
+    pub(crate) fn patches_for(&self, target: &str) -> Vec<usize> {
+        let exact = self.index.exact.get(target).map(Vec::as_slice).unwrap_or_default();
+        let mut dynamic = self
+            .index
+            .dynamic
+            .iter()
+            .copied()
+            .filter(|&i| {
+                self.patches[i]
+                    .patch
+                    .target()
+                    .is_some_and(|x| x.can_apply(target))
+            })
+            .peekable();
+
+        if dynamic.peek().is_none() {
+            return exact.to_vec();
+        }
+
+        exact
+            .iter()
+            .copied()
+            .chain(dynamic)
+            .sorted_by_key(|&i| self.index.rank[i])
+            .dedup()
+            .collect()
+    }
+
+    /// Write the order patches are applied in, along with the priority and constraints which
+    /// placed each patch there.
+    pub fn write_load_order(&self, path: &Path) {
+        let describe = |out: &mut String, n: usize, i: usize| {
+            let loaded = &self.patches[i];
+            let targets = match loaded.patch.target() {
+                Some(x) => x.names().join(", "),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "{n:>4}. {} patch from {} (mod {}, priority {})",
+                loaded.patch.type_name(),
+                loaded.path.display(),
+                loaded.mod_id,
+                loaded.priority
+            ));
+            if !targets.is_empty() {
+                out.push_str(&format!(" on {targets}"));
+            }
+            if loaded.unified {
+                out.push_str(" [unified]");
+            }
+            out.push('\n');
+
+            let Some(meta) = loaded.patch.meta() else {
+                return;
+            };
+            if let Some(name) = &meta.name {
+                out.push_str(&format!("      named '{}:{name}'\n", loaded.mod_id));
+            }
+            if !meta.after.is_empty() {
+                out.push_str(&format!("      after {}\n", meta.after.join(", ")));
+            }
+            if !meta.before.is_empty() {
+                out.push_str(&format!("      before {}\n", meta.before.join(", ")));
+            }
+        };
+
+        let mut out = String::from(
+            "# Patches are applied top to bottom to each target they match. Copy patches come\n\
+            # first, then pattern, regex, function and range patches, each sorted by priority and\n\
+            # then by load order. Patches from [unified] files are not split into phases, and\n\
+            # `after` and `before` constraints take precedence over everything else.\n\n",
+        );
+        for (n, &i) in self.index.order.iter().enumerate() {
+            describe(&mut out, n + 1, i);
+        }
+
+        let modules = self
+            .patches
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| matches!(x.patch, Patch::Module(_)))
+            .sorted_by_key(|(_, x)| x.priority)
+            .map(|(i, _)| i)
+            .collect_vec();
+        if !modules.is_empty() {
+            out.push_str("\n# Module patches, by priority.\n\n");
+            for (n, i) in modules.into_iter().enumerate() {
+                describe(&mut out, n + 1, i);
+            }
+        }
+
+        if let Err(e) = fs::write(path, out) {
+            warn!("Failed to write the load order to {path:?}: {e}");
+        }
     }
 
     /// Determine if the provided target file / name requires patching.
@@ -59,7 +390,10 @@ impl PatchTable {
         let repo = "https://github.com/ethangreen-dev/lovely-injector";
 
         // Import the functions needed for injection
-        use crate::{apply_patches, get_log_path, getvar, reload_patches, removevar, setvar};
+        use crate::{
+            apply_patches, get_log_path, get_report, getvar, poll_reload, reload_patches,
+            removevar, setvar,
+        };
 
         preload_module(
             state,
@@ -69,59 +403,58 @@ impl PatchTable {
                 .add_var("version", env!("CARGO_PKG_VERSION"))
                 .add_var("mod_dir", mod_dir)
                 .add_var("reload_patches", reload_patches as LuaFunc)
+                .add_var("poll_reload", poll_reload as LuaFunc)
                 .add_var("apply_patches", apply_patches as LuaFunc)
                 .add_var("set_var", setvar as LuaFunc)
                 .add_var("get_var", getvar as LuaFunc)
                 .add_var("remove_var", removevar as LuaFunc)
+                .add_var("get_report", get_report as LuaFunc)
+                .add_var("args", self.args.values().clone())
                 .add_var("log_path", get_log_path().unwrap()),
         );
     }
 
-    /// Apply one or more patches onto the target's buffer.
-    /// Returns the patched content and debug info.
+    /// Evaluate `load_now` module patches which must run before the target is loaded.
+    /// Returns the outcome of every module patch that was evaluated.
     /// # Safety
     /// Unsafe due to internal unchecked usages of raw lua state.
-    pub unsafe fn apply_patches(
+    pub unsafe fn apply_module_patches(
         &self,
         target: &str,
-        buffer: &str,
         lua_state: *mut LuaState,
-    ) -> Result<(String, PatchDebug), String> { // Buffer Content, Debug info, Error message
+    ) -> Result<Vec<PatchResult>, String> {
         let target = target.strip_prefix('@').unwrap_or(target);
 
-        let module_patches = self
-            .patches
-            .iter()
-            .filter_map(|(x, prio, path)| match x {
-                Patch::Module(patch) => Some((patch, prio, path)),
-                _ => None,
-            })
-            .filter(|(x, _, _)| x.load_now)
-            .sorted_by_key(|(_, &prio, _)| prio)
-            .map(|(x, _, path)| (x, path));
+        let Some(module_patches) = self.index.load_now.get(target) else {
+            return Ok(Vec::new());
+        };
 
-        let copy_patches = self
-            .patches
-            .iter()
-            .filter_map(|(x, prio, path)| match x {
-                Patch::Copy(patch) => Some((patch, prio, path)),
-                _ => None,
-            })
-            .sorted_by_key(|(_, &prio, _)| prio)
-            .map(|(x, _, path)| (x, path));
+        let mut results = Vec::new();
+        for &patch_index in module_patches {
+            let loaded = &self.patches[patch_index];
+            let Patch::Module(patch) = &loaded.patch else {
+                continue;
+            };
+            if unsafe { patch.apply(target, lua_state, &loaded.path) }? {
+                results.push(PatchResult {
+                    patch_index,
+                    matches: None,
+                    errors: Vec::new(),
+                });
+            }
+        }
 
-        let pattern_and_regex = self
-            .patches
-            .iter()
-            .filter(|(patch, _, _)| matches!(patch, Patch::Pattern(..)))
-            .chain(
-                self.patches
-                    .iter()
-                    .filter(|(patch, _, _)| matches!(patch, Patch::Regex(..))),
-            )
-            .sorted_by_key(|(_, prio, _)| prio)
-            .map(|(patch, _, path)| (patch, path))
-            .collect_vec();
+        Ok(results)
+    }
+
+    /// Apply copy, pattern, regex, function and range patches onto the target's buffer, then
+    /// interpolate vars.
+    /// Returns the patched content and debug info. Module patches are not applied here, see
+    /// [`PatchTable::apply_module_patches`].
+    ///
+    /// Returns an error if a strict patch did not match as expected.
+    pub fn apply_patches(&self, target: &str, buffer: &str) -> Result<(String, PatchDebug), String> {
+        let target = target.strip_prefix('@').unwrap_or(target);
 
         // For display + debug use. Incremented every time a patch is applied.
         let mut patch_count = 0;
@@ -129,69 +462,122 @@ impl PatchTable {
 
         // Collect byte-based debug entries, adjust after each patch.
         let mut byte_entries: Vec<ByteDebugEntry> = Vec::new();
+        let mut results: Vec<PatchResult> = Vec::new();
+        let mut conflicts: Vec<ByteConflict> = Vec::new();
 
-        // Apply module injection patches.
-        for (patch, path) in module_patches {
-            let result = unsafe { patch.apply(target, lua_state, path) };
-
-            if result? {
-                patch_count += 1;
-            }
-        }
-
-        // Apply copy patches.
-        for (patch, path) in copy_patches {
-            let result = patch.apply(target, &mut rope, path);
-            if let Some(entry) = result {
-                // Adjust all previous entries based on this patch's edits.
-                for region in &entry.regions {
-                    for prev_entry in &mut byte_entries {
-                        prev_entry.adjust(region.start, region.delta);
+        for patch_index in self.patches_for(target) {
+            let loaded = &self.patches[patch_index];
+            let path = &loaded.path;
+            if let Some(mut entry) = loaded.patch.apply(target, &mut rope, path) {
+                if let Some(warnings) = &mut entry.warnings {
+                    if let Some(game_version) = &loaded.game_version {
+                        let hint = format!(
+                            "Patch file {} was written for game version {game_version}",
+                            path.display()
+                        );
+                        warn!("{hint}");
+                        warnings.push(hint);
                     }
                 }
 
-                patch_count += 1;
-                byte_entries.push(entry);
-            }
-        }
-
-        for (patch, path) in pattern_and_regex {
-            let result = match patch {
-                Patch::Pattern(x) => x.apply(target, &mut rope, path),
-                Patch::Regex(x) => x.apply(target, &mut rope, path),
-                _ => unreachable!(),
-            };
+                // Strict mode turns patches which matched nothing, or not `times` times, into
+                // errors. Other warnings are left as they are.
+                let status = PatchStatus::from_matches(entry.matches, loaded.patch.times());
+                if status != PatchStatus::Applied && (self.strict || loaded.strict) {
+                    return Err(format!(
+                        "Strict patch from {} failed on target '{target}':\n{}",
+                        path.display(),
+                        entry.warnings.iter().flatten().join("\n")
+                    ));
+                }
 
-            if let Some(entry) = result {
-                // Adjust all previous entries based on this patch's edits.
+                // Check each of this patch's edits against the code inserted by earlier patches
+                // of other mods, then adjust all previous entries based on it.
                 for region in &entry.regions {
+                    let found = byte_entries
+                        .iter()
+                        .zip(&results)
+                        .enumerate()
+                        .filter_map(|(earlier, (prev_entry, prev_result))| {
+                            let prev = &self.patches[prev_result.patch_index];
+                            if prev.mod_id == loaded.mod_id {
+                                return None;
+                            }
+                            let anchored = !matches!(prev.patch, Patch::Copy(_))
+                                && !matches!(loaded.patch, Patch::Copy(_));
+                            let kind =
+                                ConflictKind::between(&prev_entry.regions, region, anchored)?;
+                            Some(ByteConflict {
+                                kind,
+                                earlier,
+                                later: byte_entries.len(),
+                                region: region.clone(),
+                            })
+                        })
+                        .collect_vec();
+
                     for prev_entry in &mut byte_entries {
                         prev_entry.adjust(region.start, region.delta);
                     }
+                    for conflict in &mut conflicts {
+                        conflict.region.adjust(region.start, region.delta);
+                    }
+                    conflicts.extend(found);
                 }
 
                 patch_count += 1;
+                results.push(PatchResult {
+                    patch_index,
+                    matches: entry.matches,
+                    errors: Vec::new(),
+                });
                 byte_entries.push(entry);
             }
         }
 
+        // The text each patch inserted, in final coordinates, so that vars resolve against the mod
+        // which inserted them. Later patches take precedence over earlier ones.
+        let owners = byte_entries
+            .iter()
+            .zip(&results)
+            .flat_map(|(entry, result)| {
+                entry.regions.iter().map(|x| (x.start..x.end, result.patch_index))
+            })
+            .collect_vec();
+
         // Convert byte entries to line-based debug info using final rope state.
-        let debug = PatchDebug::from_byte_entries(target, byte_entries, &rope);
+        let mut debug = PatchDebug::from_byte_entries(target, byte_entries, conflicts, &rope);
+        debug.results = results;
+        for conflict in &debug.conflicts {
+            conflict.log(target);
+        }
 
-        let mut patched_lines = {
-            let inner = rope.to_string();
-            inner.split_inclusive('\n').map(String::from).collect_vec()
+        // Apply variable interpolation. Vars which can't be resolved are left in place and
+        // reported against the patch which inserted them, the rest of the buffer is unaffected.
+        let owner_of = |offset: usize| {
+            let (_, i) = owners.iter().rev().find(|(range, _)| range.contains(&offset))?;
+            Some(*i)
         };
-
-        // Apply variable interpolation.
-        // TODO I don't think it's necessary to split into lines
-        // and convert the rope to Strings? seems overcomplicated
-        for line in patched_lines.iter_mut() {
-            vars::apply_var_interp(line, &self.vars);
+        let runtime = self.runtime_vars.read().unwrap().interpolated();
+        let (patched, errors) =
+            vars::apply_var_interp(&rope.to_string(), &self.vars, &runtime, |offset| {
+                let loaded = &self.patches[owner_of(offset)?];
+                Some((&*loaded.builtins, loaded.path.as_path()))
+            });
+        for (offset, error) in errors {
+            let error = format!("Failed to interpolate var in '{target}': {error}");
+            warn!("{error}");
+            let owned = owner_of(offset)
+                .and_then(|i| debug.results.iter().position(|x| x.patch_index == i));
+            if let Some(position) = owned {
+                debug.results[position].errors.push(error.clone());
+                debug.entries[position]
+                    .warnings
+                    .get_or_insert_with(Vec::new)
+                    .push(error);
+            }
         }
 
-        let patched = patched_lines.concat();
-
         if patch_count == 1 {
             info!("Applied 1 patch to '{target}'");
         } else {
@@ -201,3 +587,455 @@ impl PatchTable {
         Ok((patched, debug))
     }
 }
+
+/// Parse the patched buffer, mapping syntax errors back to the patch which introduced the
+/// offending line. Buffers which no patch has modified are left to Lua.
+pub fn validate_patched(target: &str, patched: &str, debug: &PatchDebug) -> Result<(), String> {
+    if debug.entries.iter().all(|x| x.regions.is_empty()) {
+        return Ok(());
+    }
+
+    let target = target.strip_prefix('@').unwrap_or(target);
+    lua_parser::parse(patched).map(|_| ()).map_err(|e| {
+        format!(
+            "Patched target '{target}' has a syntax error at line {}: {}\n{}",
+            e.line,
+            e.message,
+            debug.explain_line(e.line)
+        )
+    })
+}
+
+/// Point a syntax error reported by Lua for a patched buffer at the patch responsible for the
+/// offending line. Returns `None` if no patch modified the buffer or the message has no line.
+pub fn explain_syntax_error(message: &str, debug: &PatchDebug) -> Option<String> {
+    if debug.entries.iter().all(|x| x.regions.is_empty()) {
+        return None;
+    }
+
+    let line = ERROR_LINE_RE.captures(message)?[1].parse().ok()?;
+    Some(debug.explain_line(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn load_table(patch_toml: &str) -> (TempDir, PatchTable) {
+        load_mods(&[("mod", patch_toml.to_string())])
+    }
+
+    /// Writes each `(dir, lovely.toml)` pair as a mod and loads them all.
+    fn load_mods(mods: &[(&str, String)]) -> (TempDir, PatchTable) {
+        let temp = TempDir::new().unwrap();
+        for (dir, toml) in mods {
+            let m = temp.path().join(dir);
+            fs::create_dir_all(&m).unwrap();
+            fs::write(m.join("lovely.toml"), toml).unwrap();
+        }
+
+        let table = PatchTable::load(temp.path()).unwrap();
+        (temp, table)
+    }
+
+    const STACK_TOML: &str = r#"
+[manifest]
+version = "1.0.0"
+
+[vars]
+GREETING = "hello"
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "append"
+payload = "print('{{lovely:GREETING}}')"
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local y = 2"
+match_indent = true
+
+[[patches]]
+[patches.regex]
+target = "main.lua"
+pattern = 'x = (?<val>\d)'
+position = "at"
+root_capture = "val"
+payload = "3"
+"#;
+
+    #[test]
+    fn applies_patch_stack_without_lua() {
+        let (_temp, table) = load_table(STACK_TOML);
+
+        assert!(table.needs_patching("@main.lua"));
+        let (patched, debug) = table.apply_patches("@main.lua", "local x = 1\n").unwrap();
+
+        assert_eq!(patched, "local x = 3\nlocal y = 2\n\nprint('hello')");
+        assert_eq!(debug.entries.len(), 3);
+    }
+
+    #[test]
+    fn untargeted_buffer_is_unchanged() {
+        let (_temp, table) = load_table(STACK_TOML);
+
+        assert!(!table.needs_patching("other.lua"));
+        let (patched, debug) = table.apply_patches("other.lua", "local x = 1\n").unwrap();
+
+        assert_eq!(patched, "local x = 1\n");
+        assert!(debug.entries.is_empty());
+    }
+
+    #[test]
+    fn strict_manifest_turns_no_match_into_error() {
+        let (_temp, table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+strict = true
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local z = 1"
+position = "after"
+payload = "local y = 2"
+match_indent = true
+"#);
+
+        let err = table.apply_patches("main.lua", "local x = 1\n").unwrap_err();
+        assert!(err.contains("resulted in no matches"));
+    }
+
+    #[test]
+    fn strict_table_turns_times_mismatch_into_error() {
+        let (_temp, mut table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local y = 2"
+match_indent = true
+times = 2
+"#);
+
+        assert!(table.apply_patches("main.lua", "local x = 1\n").is_ok());
+
+        table.strict = true;
+        let err = table.apply_patches("main.lua", "local x = 1\n").unwrap_err();
+        assert!(err.contains("resulted in 1 matches, wanted 2"));
+    }
+
+    #[test]
+    fn strict_ignores_warnings_of_matched_patches() {
+        let (_temp, table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+strict = true
+game_version = "1.0.1"
+
+[[patches]]
+[patches.range]
+target = "main.lua"
+start = "function a()"
+end = "end"
+action = "delete"
+"#);
+
+        let (patched, debug) = table
+            .apply_patches("main.lua", "function a()\n  x()\nend\nfunction a()\n")
+            .unwrap();
+        assert_eq!(patched, "function a()\nend\nfunction a()\n");
+        assert!(debug.entries[0].warnings.as_ref().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn glob_and_regex_targets_match_many_names() {
+        let (_temp, table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.copy]
+target = "functions/*.lua"
+position = "append"
+payload = "-- glob"
+
+[[patches]]
+[patches.copy]
+target = { regex = 'engine/(ui|sound)\.lua' }
+position = "append"
+payload = "-- regex"
+"#);
+
+        assert!(table.needs_patching("functions/common_events.lua"));
+        assert!(table.needs_patching("@engine/ui.lua"));
+        assert!(!table.needs_patching("engine/ui.lua.bak"));
+        assert!(!table.needs_patching("main.lua"));
+
+        let (patched, _) = table.apply_patches("functions/misc.lua", "local x = 1").unwrap();
+        assert_eq!(patched, "local x = 1\n-- glob");
+        let (patched, _) = table.apply_patches("engine/sound.lua", "local x = 1").unwrap();
+        assert_eq!(patched, "local x = 1\n-- regex");
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_patch() {
+        let (_temp, table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local = 2"
+match_indent = true
+"#);
+
+        let (patched, debug) = table.apply_patches("main.lua", "local x = 1\n").unwrap();
+        let err = validate_patched("main.lua", &patched, &debug).unwrap_err();
+        assert!(err.contains("syntax error at line 2: <name> expected near '='"));
+        assert!(err.contains("Line 2 was inserted by the pattern patch from mod/lovely.toml"));
+
+        let (patched, debug) = table.apply_patches("main.lua", "local x = 1\n").unwrap();
+        let unbalanced = patched.replace("local = 2", "if x then");
+        let err = validate_patched("main.lua", &unbalanced, &debug).unwrap_err();
+        assert!(err.contains("the closest patched lines before it (2-2)"));
+
+        assert!(validate_patched("main.lua", "local x = 1\n", &PatchDebug::new("main.lua")).is_ok());
+    }
+
+    #[test]
+    fn lua_syntax_errors_point_at_the_patch() {
+        let (_temp, table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local = 2"
+match_indent = true
+"#);
+
+        let (_, debug) = table.apply_patches("main.lua", "local x = 1\n").unwrap();
+        let message = "[string \"main.lua\"]:2: <name> expected near '='";
+        let explanation = explain_syntax_error(message, &debug).unwrap();
+        assert!(explanation.contains("Line 2 was inserted by the pattern patch from mod/lovely.toml"));
+
+        assert!(explain_syntax_error("main.lua: out of memory", &debug).is_none());
+        assert!(explain_syntax_error(message, &PatchDebug::new("main.lua")).is_none());
+    }
+
+    #[test]
+    fn index_keeps_application_order() {
+        let (_temp, table) = load_table(r#"
+[manifest]
+version = "1.0.0"
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local y = 2"
+match_indent = true
+
+[[patches]]
+[patches.copy]
+target = "*.lua"
+position = "append"
+payload = "-- glob"
+
+[[patches]]
+[patches.regex]
+target = ["main.lua", "main.lua"]
+pattern = "x"
+position = "at"
+payload = "z"
+"#);
+
+        assert_eq!(table.patches_for("main.lua"), vec![1, 0, 2]);
+        assert_eq!(table.patches_for("other.lua"), vec![1]);
+        assert!(table.patches_for("main.txt").is_empty());
+    }
+
+    #[test]
+    fn constraints_override_priority_and_load_order() {
+        let toml = |dir: &str, manifest: &str, patch: &str| format!(r#"
+[manifest]
+version = "1.0.0"
+{manifest}
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "append"
+payload = "-- {dir}"
+{patch}
+"#);
+        let (temp, table) = load_mods(&[
+            ("a_first", toml("a_first", "id = \"first\"", "after = [\"second:tail\"]")),
+            ("b_second", toml("b_second", "id = \"second\"\npriority = 10", "name = \"tail\"")),
+        ]);
+        let (patched, _) = table.apply_patches("main.lua", "x").unwrap();
+        assert_eq!(patched, "x\n-- b_second\n-- a_first");
+
+        let path = temp.path().join("load-order.txt");
+        table.write_load_order(&path);
+        let order = fs::read_to_string(path).unwrap();
+        let second = order.find("b_second/lovely.toml").unwrap();
+        assert!(second < order.find("a_first/lovely.toml").unwrap());
+        assert!(order.contains("named 'second:tail'"));
+        assert!(order.contains("after second:tail"));
+    }
+
+    #[test]
+    fn unified_files_interleave_copy_and_pattern_patches() {
+        let toml = |unified: bool| format!(r#"
+[manifest]
+version = "1.0.0"
+unified = {unified}
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "-- *"
+position = "at"
+payload = "-- gone"
+match_indent = true
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "prepend"
+payload = "-- copied"
+"#);
+        let buffer = "-- original\nx\n";
+
+        // The copy patch is hoisted before the pattern, which then replaces its payload too.
+        let (_temp, table) = load_table(&toml(false));
+        let (patched, _) = table.apply_patches("main.lua", buffer).unwrap();
+        assert_eq!(patched.matches("-- gone").count(), 2);
+
+        let (_temp, table) = load_table(&toml(true));
+        let (patched, _) = table.apply_patches("main.lua", buffer).unwrap();
+        assert_eq!(patched.matches("-- gone").count(), 1);
+        assert!(patched.starts_with("-- copied\n"));
+    }
+
+    #[test]
+    fn overlapping_edits_are_conflicts() {
+        let toml = |patches: &str| format!("[manifest]\nversion = \"1.0.0\"\n{patches}");
+        let (_temp, table) = load_mods(&[
+            ("a_first", toml(r#"
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local a = 1"
+match_indent = true
+"#)),
+            ("b_second", toml(r#"
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local a = 1"
+position = "at"
+payload = "local b = 1"
+match_indent = true
+
+[[patches]]
+[patches.pattern]
+target = "main.lua"
+pattern = "local x = 1"
+position = "after"
+payload = "local c = 1"
+match_indent = true
+"#)),
+        ]);
+
+        let (patched, debug) = table.apply_patches("main.lua", "local x = 1\n").unwrap();
+        assert_eq!(patched, "local x = 1\nlocal c = 1\nlocal b = 1\n");
+
+        let kinds = debug.conflicts.iter().map(|x| x.kind).collect_vec();
+        assert_eq!(kinds, vec![ConflictKind::Overwrite, ConflictKind::SharedAnchor]);
+        assert!(debug.conflicts[0].earlier.file.contains("a_first"));
+        assert!(debug.conflicts[0].later.file.contains("b_second"));
+        assert_eq!(debug.conflicts[0].start_line, 3);
+    }
+
+    #[test]
+    fn vars_are_scoped_to_their_mod() {
+        let toml = |dir: &str, var: &str, payload: &str| format!(r#"
+[manifest]
+version = "1.0.0"
+
+[vars]
+VERSION = "{var}"
+
+[[patches]]
+[patches.copy]
+target = "{dir}.lua"
+position = "append"
+payload = "{payload}"
+"#);
+        let (_temp, table) = load_mods(&[
+            ("a", toml("a", "1.0", "local a = '{{lovely:VERSION}}'")),
+            ("b", toml("b", "2.0", "local b = '{{lovely:VERSION}}' .. '{{lovely:a.VERSION}}'")),
+            ("c", toml("c", "3.0", "local c = {{lovely:MISSING}} .. '{{lovely:VERSION}}'")),
+        ]);
+
+        let (patched, _) = table.apply_patches("a.lua", "").unwrap();
+        assert_eq!(patched, "\nlocal a = '1.0'");
+        let (patched, _) = table.apply_patches("b.lua", "").unwrap();
+        assert_eq!(patched, "\nlocal b = '2.0' .. '1.0'");
+
+        // The unresolved var is reported against its patch and left for Lua to trip over.
+        let (patched, debug) = table.apply_patches("c.lua", "").unwrap();
+        assert_eq!(patched, "\nlocal c = {{lovely:MISSING}} .. '3.0'");
+        let errors = &debug.results[0].errors;
+        assert!(errors[0].contains("'{{lovely:MISSING}}' at line 2: mod 'c' does not declare it"));
+    }
+
+    #[test]
+    fn mods_missing_required_args_are_skipped() {
+        let toml = |dir: &str, default: &str| format!(r#"
+[manifest]
+version = "1.0.0"
+
+[args.{dir}_seed]
+{default}
+
+[[patches]]
+[patches.copy]
+target = "main.lua"
+position = "append"
+payload = "-- {dir}"
+"#);
+        let (temp, table) = load_mods(&[
+            ("a", toml("a", "")),
+            ("b", toml("b", "default = \"1\"")),
+        ]);
+        let (patched, _) = table.apply_patches("main.lua", "").unwrap();
+        assert!(patched.contains("-- a") && patched.contains("-- b"));
+
+        let table = PatchTable::load_with_args(temp.path(), &[]).unwrap();
+        let (patched, _) = table.apply_patches("main.lua", "").unwrap();
+        assert!(!patched.contains("-- a") && patched.contains("-- b"));
+    }
+}
@@ -1,19 +1,475 @@
-use std::{collections::HashMap, sync::LazyLock};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::Path;
+use std::sync::LazyLock;
 
+use itertools::Itertools;
+use log::*;
 use regex_lite::{Captures, Regex};
 
-/// Apply valid var interpolations to the provided line.
-/// Interpolation targets are of form {{lovely:VAR_NAME}}.
-pub fn apply_var_interp(line: &mut String, vars: &HashMap<String, String>) {
-    // Cache the compiled regex.
-    let re: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{\{lovely:(\w+)\}\}").unwrap());
+use crate::LOVELY_VERSION;
 
-    let line_replaced = re.replace_all(line, |captures: &Captures| {
-        let (_, [var]) = captures.extract();
-        let Some(val) = vars.get(var) else {
-            panic!("Failed to interpolate an unregistered variable '{var}'");
+// Matches {{lovely:VAR}} and {{lovely:modid.VAR}}, either followed by an optional `:filter` and
+// an optional `|default` fallback.
+static VAR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{lovely:(?:([\w-]+)\.)?(\w+)(?::(\w+))?(?:\|([^}\n]*))?\}\}").unwrap()
+});
+
+// Vars provided by Lovely itself. They take precedence over vars declared by mods.
+pub const BUILTIN_VARS: [&str; 6] = [
+    "MOD_DIR",
+    "MOD_ID",
+    "PATCH_FILE",
+    "LOVELY_VERSION",
+    "OS",
+    "GAME_EXE",
+];
+
+static GAME_EXE: LazyLock<String> = LazyLock::new(|| {
+    env::current_exe()
+        .ok()
+        .and_then(|x| Some(x.file_name()?.to_string_lossy().to_string()))
+        .unwrap_or_default()
+});
+
+/// Built-in vars which are the same for every patch file.
+fn global_builtin(name: &str) -> Option<&'static str> {
+    match name {
+        "LOVELY_VERSION" => Some(LOVELY_VERSION),
+        "OS" => Some(env::consts::OS),
+        "GAME_EXE" => Some(&GAME_EXE),
+        _ => None,
+    }
+}
+
+/// Format a path with `/` separators, which Lua and LÖVE accept on every platform.
+fn slash_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Quote a value as a Lua string literal.
+pub fn lua_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // Always use 3 digits, so that a digit after the escape isn't read as part of it.
+            c if c.is_ascii_control() => out.push_str(&format!("\\{:03}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A `{{lovely:...}}` reference.
+struct VarRef<'t> {
+    scope: Option<&'t str>,
+    name: &'t str,
+    filter: Option<&'t str>,
+    default: Option<&'t str>,
+}
+
+/// Replace every var reference in the text with the value `resolve` returns for it, passed
+/// through the reference's filter. `resolve` is given the byte offset of the reference, and
+/// returns `Ok(None)` to leave the reference in place, or an error to fall back on the default.
+/// References which could not be resolved are left in place, and returned with their offset.
+fn substitute(
+    text: &str,
+    mut resolve: impl FnMut(usize, &VarRef) -> Result<Option<String>, String>,
+) -> (String, Vec<(usize, String)>) {
+    let mut errors = Vec::new();
+    let out = VAR_RE.replace_all(text, |captures: &Captures| {
+        let whole = captures.get(0).unwrap();
+        let var = VarRef {
+            scope: captures.get(1).map(|x| x.as_str()),
+            name: captures.get(2).unwrap().as_str(),
+            filter: captures.get(3).map(|x| x.as_str()),
+            default: captures.get(4).map(|x| x.as_str()),
         };
-        val
+
+        let line = || text[..whole.start()].matches('\n').count() + 1;
+        let value = match resolve(whole.start(), &var) {
+            Ok(x) => x,
+            Err(_) if var.default.is_some() => var.default.map(String::from),
+            Err(reason) => {
+                let error = format!("'{}' at line {}: {reason}", whole.as_str(), line());
+                errors.push((whole.start(), error));
+                None
+            }
+        };
+
+        match (value, var.filter) {
+            (None, _) => whole.as_str().to_string(),
+            (Some(x), None) => x,
+            (Some(x), Some("lua")) => lua_string(&x),
+            (Some(_), Some(filter)) => {
+                let error = format!(
+                    "'{}' at line {}: unknown filter '{filter}'",
+                    whole.as_str(),
+                    line()
+                );
+                errors.push((whole.start(), error));
+                whole.as_str().to_string()
+            }
+        }
     });
-    *line = line_replaced.to_string();
+
+    (out.into_owned(), errors)
+}
+
+/// Check if the text refers to a built-in var.
+pub fn uses_builtins(text: &str) -> bool {
+    VAR_RE
+        .captures_iter(text)
+        .any(|x| x.get(1).is_none() && BUILTIN_VARS.contains(&&x[2]))
+}
+
+/// The built-in vars of a single patch file.
+#[derive(Debug, Clone)]
+pub struct Builtins {
+    pub mod_id: String,
+    // Absolute path of the mod's directory or zip file.
+    mod_dir: String,
+    // Path of the patch file relative to the mod's directory.
+    patch_file: String,
+}
+
+impl Builtins {
+    pub fn new(mod_id: &str, mod_dir: &Path, patch_file: &Path) -> Self {
+        Self {
+            mod_id: mod_id.to_string(),
+            mod_dir: slash_path(mod_dir),
+            patch_file: slash_path(patch_file),
+        }
+    }
+
+    /// Get a built-in var by name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "MOD_DIR" => Some(&self.mod_dir),
+            "MOD_ID" => Some(&self.mod_id),
+            "PATCH_FILE" => Some(&self.patch_file),
+            _ => global_builtin(name),
+        }
+    }
+
+    /// Resolve built-in vars in every string value of a parsed patch file. Other vars are left
+    /// to be interpolated when patching.
+    pub fn resolve_in(&self, value: &mut toml::Value) -> Result<(), String> {
+        match value {
+            toml::Value::String(x) => {
+                let (resolved, errors) = substitute(x, |_, var| match var.scope {
+                    Some(_) => Ok(None),
+                    None => Ok(self.get(var.name).map(String::from)),
+                });
+                if !errors.is_empty() {
+                    return Err(errors.into_iter().map(|(_, e)| e).join("\n"));
+                }
+                *x = resolved;
+            }
+            toml::Value::Array(x) => {
+                for item in x {
+                    self.resolve_in(item)?;
+                }
+            }
+            toml::Value::Table(x) => {
+                for (_, item) in x.iter_mut() {
+                    self.resolve_in(item)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Vars declared in the `[vars]` tables of patch files, scoped to the mod which declared them.
+#[derive(Debug, Default, Clone)]
+pub struct ModVars {
+    // Var name to value, by mod id.
+    scopes: HashMap<String, HashMap<String, String>>,
+}
+
+impl ModVars {
+    /// Declare a var of a mod. If the mod already declared it with a different value, the first
+    /// declaration is kept and the collision is logged. Built-in names can't be declared.
+    pub fn declare(&mut self, mod_id: &str, name: &str, value: &str, file: &Path) {
+        if BUILTIN_VARS.contains(&name) {
+            warn!(
+                "Var '{name}' declared in {} has the name of a built-in var, ignoring it",
+                file.display()
+            );
+            return;
+        }
+
+        let scope = self.scopes.entry(mod_id.to_string()).or_default();
+        match scope.get(name) {
+            Some(existing) if existing != value => warn!(
+                "Var '{name}' of mod '{mod_id}' is declared as '{existing}' and again as '{value}' \
+                in {}, keeping '{existing}'",
+                file.display()
+            ),
+            Some(_) => (),
+            None => {
+                scope.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+
+    /// Set a var of a mod, replacing any previous value.
+    pub fn insert(&mut self, mod_id: &str, name: &str, value: &str) {
+        self.scopes
+            .entry(mod_id.to_string())
+            .or_default()
+            .insert(name.to_string(), value.to_string());
+    }
+
+    /// Get a var of a mod.
+    pub fn get(&self, mod_id: &str, name: &str) -> Option<&str> {
+        self.scopes.get(mod_id)?.get(name).map(String::as_str)
+    }
+
+    /// Get a var as seen from a patch of `mod_id`: either a var of that mod, or a var of
+    /// another mod when qualified as `modid.VAR`.
+    pub fn lookup(&self, mod_id: &str, name: &str) -> Option<&str> {
+        match name.split_once('.') {
+            Some((scope, name)) => self.get(scope, name),
+            None => self.get(mod_id, name),
+        }
+    }
+
+    /// The ids of every mod which declares the var.
+    fn declared_by(&self, name: &str) -> Vec<&str> {
+        self.scopes
+            .iter()
+            .filter(|(_, vars)| vars.contains_key(name))
+            .map(|(id, _)| id.as_str())
+            .sorted()
+            .collect()
+    }
+
+    /// Every var as (mod id, name, value).
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.scopes.iter().flat_map(|(id, vars)| {
+            vars.iter()
+                .map(move |(name, value)| (id.as_str(), name.as_str(), value.as_str()))
+        })
+    }
+}
+
+/// Interpolate vars into the provided text.
+/// Interpolation targets are of form {{lovely:VAR_NAME}}, which refers to a built-in var or a var
+/// of the mod which inserted the text, or {{lovely:modid.VAR_NAME}}, which refers to a var of the
+/// named mod. Either form can be followed by `:lua` to quote the value as a Lua string literal, and
+/// end in `|default`, used when the var is not declared.
+///
+/// `owner` returns the built-in vars and path of the patch file which inserted the text at a byte
+/// offset. Text which no patch inserted can only use global built-ins and unqualified vars that a
+/// single mod declares.
+///
+/// `runtime` holds the vars set at runtime to feed interpolation, see
+/// [`RuntimeVars::interpolated`](crate::runtime_vars::RuntimeVars::interpolated), and
+/// `{{lovely:VAR.key}}` refers to an entry of a runtime table. A var resolves to the first of:
+/// a built-in var, a runtime var, a var declared by the mod, the `|default`. When a runtime table
+/// is named like a mod id, `{{lovely:modid.VAR}}` keeps referring to the mod's var.
+///
+/// Vars which could not be resolved are left in place. They are returned along with the byte
+/// offset of their reference in `text`, so that they can be reported against the patch which
+/// inserted them.
+pub fn apply_var_interp<'a>(
+    text: &str,
+    vars: &ModVars,
+    runtime: &BTreeMap<String, String>,
+    owner: impl Fn(usize) -> Option<(&'a Builtins, &'a Path)>,
+) -> (String, Vec<(usize, String)>) {
+    let resolve = |offset: usize, var: &VarRef| {
+        let name = var.name;
+        let value = match (var.scope, owner(offset)) {
+            (Some(scope), _) => {
+                let key = format!("{scope}.{name}");
+                match (vars.get(scope, name), runtime.get(&key)) {
+                    (Some(x), Some(_)) => {
+                        warn!(
+                            "Runtime var '{key}' has the name of var '{name}' of mod '{scope}', \
+                            using the mod's var"
+                        );
+                        Ok(x)
+                    }
+                    (Some(x), None) => Ok(x),
+                    (None, Some(x)) => Ok(x.as_str()),
+                    (None, None) => Err(format!("mod '{scope}' does not declare it")),
+                }
+            }
+            (None, Some((builtins, path))) => builtins
+                .get(name)
+                .or_else(|| runtime.get(name).map(String::as_str))
+                .or_else(|| vars.get(&builtins.mod_id, name))
+                .ok_or_else(|| {
+                    format!(
+                        "mod '{}' does not declare it, used by patch from {}",
+                        builtins.mod_id,
+                        path.display()
+                    )
+                }),
+            (None, None) => match global_builtin(name).or(runtime.get(name).map(String::as_str)) {
+                Some(x) => Ok(x),
+                None => match vars.declared_by(name).as_slice() {
+                    [mod_id] => Ok(vars.get(mod_id, name).unwrap()),
+                    [] => Err("no mod declares it".to_string()),
+                    ids => Err(format!(
+                        "it is outside of any patch and declared by {}, qualify it with the mod id",
+                        ids.join(", ")
+                    )),
+                },
+            },
+        };
+        value.map(|x| Some(x.to_string()))
+    };
+
+    substitute(text, resolve)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> ModVars {
+        let mut vars = ModVars::default();
+        vars.insert("a", "VERSION", "1.0");
+        vars.insert("b", "VERSION", "2.0");
+        vars.insert("b", "NAME", "bee");
+        vars
+    }
+
+    fn builtins() -> Builtins {
+        Builtins::new("a", Path::new("/mods/a \"b\""), Path::new("lovely/patch.toml"))
+    }
+
+    fn interp<'a>(
+        text: &str,
+        owner: impl Fn(usize) -> Option<(&'a Builtins, &'a Path)>,
+    ) -> Result<String, String> {
+        let (out, errors) = apply_var_interp(text, &vars(), &BTreeMap::new(), owner);
+        if errors.is_empty() {
+            Ok(out)
+        } else {
+            Err(errors.into_iter().map(|(_, e)| e).join("\n"))
+        }
+    }
+
+    #[test]
+    fn vars_are_scoped_to_the_inserting_mod() {
+        let a = builtins();
+        let owner = |_: usize| Some((&a, Path::new("a/lovely/patch.toml")));
+        let text = "{{lovely:VERSION}} {{lovely:b.VERSION}}";
+
+        assert_eq!(interp(text, owner).unwrap(), "1.0 2.0");
+    }
+
+    #[test]
+    fn defaults_and_missing_vars() {
+        let a = builtins();
+        let owner = |_: usize| Some((&a, Path::new("a/lovely/patch.toml")));
+        let text = "{{lovely:DEBUG|false}} {{lovely:VERSION|0}}";
+        assert_eq!(interp(text, owner).unwrap(), "false 1.0");
+
+        let err = interp("x\n{{lovely:NAME}}", owner).unwrap_err();
+        assert!(err.contains("'{{lovely:NAME}}' at line 2: mod 'a' does not declare it"));
+
+        let runtime = BTreeMap::new();
+        let (patched, errors) = apply_var_interp("x\n{{lovely:NAME}}", &vars(), &runtime, owner);
+        assert_eq!(patched, "x\n{{lovely:NAME}}");
+        assert_eq!(errors[0].0, 2);
+    }
+
+    #[test]
+    fn unowned_text_needs_an_unambiguous_var() {
+        let owner = |_: usize| None::<(&Builtins, &Path)>;
+        assert_eq!(interp("{{lovely:NAME}}", owner).unwrap(), "bee");
+
+        let err = interp("{{lovely:VERSION}}", owner).unwrap_err();
+        assert!(err.contains("declared by a, b"));
+    }
+
+    #[test]
+    fn runtime_vars_override_declared_vars() {
+        let a = builtins();
+        let owner = |_: usize| Some((&a, Path::new("a/lovely/patch.toml")));
+        let runtime = BTreeMap::from([
+            ("VERSION".to_string(), "runtime".to_string()),
+            ("HD".to_string(), "true".to_string()),
+            ("CONFIG.theme".to_string(), "dark".to_string()),
+        ]);
+        let text = "{{lovely:VERSION}} {{lovely:HD}} {{lovely:CONFIG.theme:lua}}";
+
+        let (patched, _) = apply_var_interp(text, &vars(), &runtime, owner);
+        assert_eq!(patched, r#"runtime true "dark""#);
+
+        // Built-in vars can't be overridden.
+        let runtime = BTreeMap::from([("MOD_ID".to_string(), "runtime".to_string())]);
+        let (patched, _) = apply_var_interp("{{lovely:MOD_ID}}", &vars(), &runtime, owner);
+        assert_eq!(patched, "a");
+    }
+
+    #[test]
+    fn runtime_tables_named_like_mods_keep_mod_vars() {
+        let a = builtins();
+        let owner = |_: usize| Some((&a, Path::new("a/lovely/patch.toml")));
+        let runtime = BTreeMap::from([
+            ("b.NAME".to_string(), "runtime".to_string()),
+            ("b.COLOR".to_string(), "red".to_string()),
+        ]);
+        let text = "{{lovely:b.NAME}} {{lovely:b.VERSION}} {{lovely:b.COLOR}}";
+
+        let (patched, _) = apply_var_interp(text, &vars(), &runtime, owner);
+        assert_eq!(patched, "bee 2.0 red");
+    }
+
+    #[test]
+    fn collisions_keep_the_first_value() {
+        let mut vars = ModVars::default();
+        vars.declare("a", "VERSION", "1.0", Path::new("a/lovely/one.toml"));
+        vars.declare("a", "VERSION", "2.0", Path::new("a/lovely/two.toml"));
+        vars.declare("a", "MOD_DIR", "elsewhere", Path::new("a/lovely/one.toml"));
+
+        assert_eq!(vars.lookup("a", "VERSION"), Some("1.0"));
+        assert_eq!(vars.lookup("b", "a.VERSION"), Some("1.0"));
+        assert_eq!(vars.lookup("a", "MOD_DIR"), None);
+    }
+
+    #[test]
+    fn builtins_and_lua_filter() {
+        let a = builtins();
+        let owner = |_: usize| Some((&a, Path::new("a/lovely/patch.toml")));
+        let text = "{{lovely:MOD_ID}} {{lovely:PATCH_FILE}} {{lovely:MOD_DIR:lua}} {{lovely:OS}}";
+        let expected = format!(r#"a lovely/patch.toml "/mods/a \"b\"" {}"#, env::consts::OS);
+        assert_eq!(interp(text, owner).unwrap(), expected);
+
+        assert_eq!(lua_string("C:\\Mods\n\u{1}2"), r#""C:\\Mods\n\0012""#);
+        let err = interp("{{lovely:MOD_ID:json}}", owner).unwrap_err();
+        assert!(err.contains("unknown filter 'json'"));
+    }
+
+    #[test]
+    fn resolves_builtins_in_parsed_values() {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+source = '{{lovely:MOD_DIR}}\nested.lua'
+payloads = ["{{lovely:MOD_ID}} {{lovely:VERSION}}"]
+"#,
+        )
+        .unwrap();
+        builtins().resolve_in(&mut value).unwrap();
+
+        assert_eq!(value["source"].as_str(), Some(r#"/mods/a "b"\nested.lua"#));
+        assert_eq!(value["payloads"][0].as_str(), Some("a {{lovely:VERSION}}"));
+        assert!(uses_builtins("{{lovely:GAME_EXE:lua}}"));
+        assert!(!uses_builtins("{{lovely:other.MOD_ID}} {{lovely:VERSION}}"));
+    }
 }
@@ -0,0 +1,321 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::dump::DebugPatchType;
+use crate::patch::table::PatchTable;
+use crate::patch::{Patch, Target};
+use crate::sys::{LuaState, LuaTable, Pushable};
+use crate::Lovely;
+
+/// How often recorded outcomes are written to the report file.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The outcome of a single patch applied to a single buffer.
+#[derive(Debug, Clone)]
+pub struct PatchResult {
+    // Index of the patch within `PatchTable::patches`.
+    pub patch_index: usize,
+    // The number of matches found by pattern / regex patches. `None` for other patch types.
+    pub matches: Option<usize>,
+    // Vars inserted by this patch which could not be interpolated, and were left in place.
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchStatus {
+    // The target of this patch has not been loaded (yet).
+    Pending,
+    Applied,
+    NoMatches,
+    // The patch matched, but not as many times as `times` asked for.
+    TimesMismatch,
+    // The patch was applied, but inserted vars which could not be interpolated.
+    UnresolvedVars,
+}
+
+impl PatchStatus {
+    pub fn from_matches(matches: Option<usize>, times: Option<usize>) -> Self {
+        match (matches, times) {
+            (Some(0), _) => PatchStatus::NoMatches,
+            (Some(found), Some(times)) if found != times => PatchStatus::TimesMismatch,
+            _ => PatchStatus::Applied,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            PatchStatus::Pending => "pending",
+            PatchStatus::Applied => "applied",
+            PatchStatus::NoMatches => "no_matches",
+            PatchStatus::TimesMismatch => "times_mismatch",
+            PatchStatus::UnresolvedVars => "unresolved_vars",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReportEntry {
+    #[serde(skip)]
+    patch_index: usize,
+    // Glob and regex targets match many names, so outcomes are matched against the spec.
+    #[serde(skip)]
+    target_spec: Option<Target>,
+    // The patch file this patch was declared in, relative to the mod directory.
+    pub file: String,
+    pub patch_type: DebugPatchType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    // `None` for module patches which are not bound to a target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub times: Option<usize>,
+    pub status: PatchStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+}
+
+/// An aggregated report of every loaded patch and how it fared against its target(s).
+#[derive(Serialize, Debug, Default)]
+pub struct PatchReport {
+    pub entries: Vec<ReportEntry>,
+    // Set when outcomes were recorded since the report was last written.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl PatchReport {
+    /// Create a report with a pending entry for each patch and target pair in the table.
+    pub fn new(table: &PatchTable) -> Self {
+        let mut entries = Vec::new();
+
+        for (patch_index, loaded) in table.patches.iter().enumerate() {
+            let patch = &loaded.patch;
+            let targets = match patch.target() {
+                Some(target) => target.split().into_iter().map(Some).collect(),
+                None => match patch {
+                    Patch::Module(x) if x.load_now => {
+                        vec![x.before.as_deref().map(|x| Target::Single(x.into()))]
+                    }
+                    _ => vec![None],
+                },
+            };
+
+            for target in targets {
+                entries.push(ReportEntry {
+                    patch_index,
+                    file: loaded.path.display().to_string(),
+                    patch_type: patch.debug_type(),
+                    pattern: patch.anchor(),
+                    target: target.as_ref().map(|x| x.names().concat()),
+                    target_spec: target,
+                    matches: None,
+                    times: patch.times(),
+                    status: PatchStatus::Pending,
+                    errors: Vec::new(),
+                });
+            }
+        }
+
+        PatchReport {
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// Record the outcomes of patches applied onto the target buffer. Patches with glob or regex
+    /// targets get an entry for each concrete target they were applied to.
+    pub fn record(&mut self, target: &str, results: &[PatchResult]) {
+        let target = target.strip_prefix('@').unwrap_or(target);
+
+        for result in results {
+            let Some(index) = self.entry_index(target, result.patch_index) else {
+                continue;
+            };
+
+            let entry = &mut self.entries[index];
+            entry.matches = result.matches;
+            entry.status = match result.errors.is_empty() {
+                true => PatchStatus::from_matches(result.matches, entry.times),
+                false => PatchStatus::UnresolvedVars,
+            };
+            entry.errors = result.errors.clone();
+            self.dirty = true;
+        }
+    }
+
+    /// Find the entry of the patch for the target, splitting off a new one if the patch has a
+    /// glob or regex target whose entry belongs to a different concrete target.
+    fn entry_index(&mut self, target: &str, patch_index: usize) -> Option<usize> {
+        let is_patch = |x: &ReportEntry| x.patch_index == patch_index;
+        if let Some(index) = self
+            .entries
+            .iter()
+            .position(|x| is_patch(x) && x.target.as_deref() == Some(target))
+        {
+            return Some(index);
+        }
+
+        let index = self.entries.iter().position(|x| {
+            is_patch(x) && x.target_spec.as_ref().is_none_or(|x| x.can_apply(target))
+        })?;
+        let spec = &self.entries[index];
+        if !spec.target_spec.as_ref().is_some_and(Target::is_dynamic) {
+            return Some(index);
+        }
+
+        // The pending entry of a glob or regex target is taken over by the first target it
+        // matches. Later targets get entries of their own.
+        if spec.status == PatchStatus::Pending {
+            self.entries[index].target = Some(target.to_string());
+            return Some(index);
+        }
+        let split = ReportEntry {
+            patch_index,
+            target_spec: spec.target_spec.clone(),
+            file: spec.file.clone(),
+            patch_type: spec.patch_type.clone(),
+            pattern: spec.pattern.clone(),
+            target: Some(target.to_string()),
+            matches: None,
+            times: spec.times,
+            status: PatchStatus::Pending,
+            errors: Vec::new(),
+        };
+        self.entries.insert(index + 1, split);
+        Some(index + 1)
+    }
+
+    /// Write the report if outcomes were recorded since it was last written.
+    pub fn flush(&mut self, path: &Path) {
+        if self.dirty {
+            self.write(path);
+            self.dirty = false;
+        }
+    }
+
+    /// Write the report as JSON to the provided path.
+    pub fn write(&self, path: &Path) {
+        match serde_json::to_string_pretty(self) {
+            Ok(json) => {
+                if let Err(e) = fs::write(path, json) {
+                    log::error!("Failed to write patch report to {path:?}: {e:?}");
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to serialize patch report: {e:?}");
+            }
+        }
+    }
+}
+
+/// Spawn a thread which periodically writes the report, so that loading a target only has to
+/// record its outcomes in memory.
+pub fn spawn_writer(lovely: &'static Lovely, interval: Duration) {
+    let path = lovely.report_path();
+
+    let result = thread::Builder::new()
+        .name("lovely-report".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            lovely.report.write().unwrap().flush(&path);
+        });
+
+    if let Err(e) = result {
+        log::error!("Failed to spawn the patch report writer thread: {e:?}");
+    }
+}
+
+impl Pushable for ReportEntry {
+    unsafe fn push(&self, state: *mut LuaState) {
+        let mut table = LuaTable::new()
+            .add_var("file", self.file.clone())
+            .add_var("patch_type", self.patch_type.as_str())
+            .add_var("status", self.status.as_str());
+
+        if let Some(pattern) = &self.pattern {
+            table = table.add_var("pattern", pattern.clone());
+        }
+        if let Some(target) = &self.target {
+            table = table.add_var("target", target.clone());
+        }
+        if let Some(matches) = self.matches {
+            table = table.add_var("matches", matches as isize);
+        }
+        if let Some(times) = self.times {
+            table = table.add_var("times", times as isize);
+        }
+        if !self.errors.is_empty() {
+            table = table.add_var("errors", self.errors.clone());
+        }
+
+        table.push(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_from_matches() {
+        assert_eq!(PatchStatus::from_matches(None, None), PatchStatus::Applied);
+        assert_eq!(PatchStatus::from_matches(Some(0), None), PatchStatus::NoMatches);
+        assert_eq!(PatchStatus::from_matches(Some(0), Some(1)), PatchStatus::NoMatches);
+        assert_eq!(PatchStatus::from_matches(Some(2), None), PatchStatus::Applied);
+        assert_eq!(PatchStatus::from_matches(Some(2), Some(2)), PatchStatus::Applied);
+        assert_eq!(PatchStatus::from_matches(Some(3), Some(2)), PatchStatus::TimesMismatch);
+    }
+
+    fn result(matches: usize) -> PatchResult {
+        PatchResult {
+            patch_index: 0,
+            matches: Some(matches),
+            errors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn glob_targets_get_an_entry_per_target() {
+        let mut report = PatchReport {
+            entries: vec![ReportEntry {
+                patch_index: 0,
+                target_spec: Some(Target::Single("functions/*.lua".into())),
+                file: "mod/lovely.toml".to_string(),
+                patch_type: DebugPatchType::Pattern,
+                pattern: Some("local x = 1".to_string()),
+                target: Some("functions/*.lua".to_string()),
+                matches: None,
+                times: None,
+                status: PatchStatus::Pending,
+                errors: Vec::new(),
+            }],
+            dirty: false,
+        };
+
+        report.record("@functions/a.lua", &[result(0)]);
+        report.record("functions/b.lua", &[result(2)]);
+        report.record("main.lua", &[result(1)]);
+
+        let outcomes = report
+            .entries
+            .iter()
+            .map(|x| (x.target.as_deref().unwrap(), x.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("functions/a.lua", PatchStatus::NoMatches),
+                ("functions/b.lua", PatchStatus::Applied),
+            ]
+        );
+        assert!(report.dirty);
+    }
+}
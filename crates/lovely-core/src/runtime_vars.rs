@@ -0,0 +1,238 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{c_int, CStr};
+
+use crate::sys::{self, LuaState, LuaStateTrait, Pushable};
+
+/// A key of a runtime table, either an array index or a string.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TableKey {
+    Index(i64),
+    Name(String),
+}
+
+impl TableKey {
+    fn as_var(&self) -> String {
+        match self {
+            TableKey::Index(x) => x.to_string(),
+            TableKey::Name(x) => x.clone(),
+        }
+    }
+}
+
+impl Pushable for TableKey {
+    unsafe fn push(&self, state: *mut LuaState) {
+        match self {
+            TableKey::Index(x) => sys::lua_pushnumber(state, *x as f64),
+            TableKey::Name(x) => x.push(state),
+        }
+    }
+}
+
+/// A value set with `lovely.set_var`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeValue {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    // A table of strings, numbers and booleans. Nested tables are not supported.
+    Table(BTreeMap<TableKey, RuntimeValue>),
+}
+
+impl RuntimeValue {
+    /// Read the value at an absolute stack index. Returns an error for the types which can't be
+    /// stored, such as nil, functions and nested tables.
+    ///
+    /// # Safety
+    /// Directly interacts with native Lua state.
+    pub unsafe fn read(state: *mut LuaState, index: c_int) -> Result<Self, String> {
+        match sys::lua_type(state, index) {
+            sys::LUA_TBOOLEAN => Ok(RuntimeValue::Bool(sys::lua_toboolean(state, index) != 0)),
+            sys::LUA_TNUMBER => Ok(RuntimeValue::Number(sys::lua_tonumber(state, index))),
+            sys::LUA_TSTRING => Ok(RuntimeValue::String(state.to_string(index))),
+            sys::LUA_TTABLE => Self::read_table(state, index),
+            other => Err(format!(
+                "values of type {} can't be stored",
+                type_name(state, other)
+            )),
+        }
+    }
+
+    unsafe fn read_table(state: *mut LuaState, index: c_int) -> Result<Self, String> {
+        let mut table = BTreeMap::new();
+
+        sys::lua_pushnil(state);
+        while sys::lua_next(state, index) != 0 {
+            // The key is at -2 and the value at -1. Pop the value, keeping the key for lua_next.
+            let entry = Self::read_entry(state);
+            sys::lua_settop(state, -2);
+            match entry {
+                Ok((key, value)) => {
+                    table.insert(key, value);
+                }
+                Err(e) => {
+                    sys::lua_settop(state, -2);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(RuntimeValue::Table(table))
+    }
+
+    unsafe fn read_entry(state: *mut LuaState) -> Result<(TableKey, RuntimeValue), String> {
+        // Keys are checked by type, as lua_tolstring would convert a number key in place and
+        // break the traversal.
+        let key = match sys::lua_type(state, -2) {
+            sys::LUA_TNUMBER => {
+                let x = sys::lua_tonumber(state, -2);
+                if x.fract() != 0.0 {
+                    return Err(format!("table key {x} is not an integer"));
+                }
+                TableKey::Index(x as i64)
+            }
+            sys::LUA_TSTRING => TableKey::Name(state.to_string(-2)),
+            other => {
+                return Err(format!("{} table keys can't be stored", type_name(state, other)))
+            }
+        };
+
+        let value = match sys::lua_type(state, -1) {
+            sys::LUA_TTABLE => return Err(format!("nested table at key '{}'", key.as_var())),
+            _ => Self::read(state, sys::lua_gettop(state))?,
+        };
+
+        Ok((key, value))
+    }
+
+    /// The value as interpolated into patches. Whole numbers are written without a fraction, as
+    /// Lua's `tostring` does. Tables have no single value, see [`RuntimeVars::interpolated`].
+    pub fn as_var(&self) -> Option<String> {
+        match self {
+            RuntimeValue::String(x) => Some(x.clone()),
+            RuntimeValue::Number(x) if x.fract() == 0.0 && x.abs() < 1e15 => {
+                Some((*x as i64).to_string())
+            }
+            RuntimeValue::Number(x) => Some(x.to_string()),
+            RuntimeValue::Bool(x) => Some(x.to_string()),
+            RuntimeValue::Table(_) => None,
+        }
+    }
+}
+
+impl Pushable for RuntimeValue {
+    unsafe fn push(&self, state: *mut LuaState) {
+        match self {
+            RuntimeValue::String(x) => x.push(state),
+            RuntimeValue::Number(x) => sys::lua_pushnumber(state, *x),
+            RuntimeValue::Bool(x) => x.push(state),
+            RuntimeValue::Table(x) => {
+                sys::lua_createtable(state, 0, x.len().try_into().unwrap());
+                for (key, value) in x {
+                    key.push(state);
+                    value.push(state);
+                    sys::lua_settable(state, -3);
+                }
+            }
+        }
+    }
+}
+
+unsafe fn type_name(state: *mut LuaState, tp: c_int) -> String {
+    CStr::from_ptr(sys::lua_typename(state, tp))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// The vars set at runtime through `lovely.set_var`. They are separate from the vars of patch
+/// files, unless set with `interpolate`, in which case they also feed the interpolation of
+/// chunks loaded afterwards.
+#[derive(Debug, Default)]
+pub struct RuntimeVars {
+    values: HashMap<String, RuntimeValue>,
+    // Names of the vars which feed interpolation.
+    interpolate: HashSet<String>,
+}
+
+impl RuntimeVars {
+    pub fn get(&self, name: &str) -> Option<&RuntimeValue> {
+        self.values.get(name)
+    }
+
+    /// Set a var, replacing any previous value. Whether it feeds interpolation is decided anew
+    /// on every set.
+    pub fn set(&mut self, name: &str, value: RuntimeValue, interpolate: bool) {
+        if interpolate {
+            self.interpolate.insert(name.to_string());
+        } else {
+            self.interpolate.remove(name);
+        }
+        self.values.insert(name.to_string(), value);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RuntimeValue> {
+        self.interpolate.remove(name);
+        self.values.remove(name)
+    }
+
+    /// The vars which feed interpolation, by name. The entries of a table `VAR` are named
+    /// `VAR.key`, so that they can be written as `{{lovely:VAR.key}}`.
+    pub fn interpolated(&self) -> BTreeMap<String, String> {
+        let mut out = BTreeMap::new();
+        for name in &self.interpolate {
+            match &self.values[name] {
+                RuntimeValue::Table(x) => {
+                    for (key, value) in x {
+                        if let Some(value) = value.as_var() {
+                            out.insert(format!("{name}.{}", key.as_var()), value);
+                        }
+                    }
+                }
+                value => {
+                    out.extend(value.as_var().map(|x| (name.clone(), x)));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_opted_in_vars_are_interpolated() {
+        let mut vars = RuntimeVars::default();
+        vars.set("HD", RuntimeValue::Bool(true), true);
+        vars.set("SCALE", RuntimeValue::Number(2.0), true);
+        vars.set("RATIO", RuntimeValue::Number(0.5), true);
+        vars.set("SECRET", RuntimeValue::String("hidden".to_string()), false);
+        vars.set(
+            "CONFIG",
+            RuntimeValue::Table(BTreeMap::from([
+                (
+                    TableKey::Name("theme".to_string()),
+                    RuntimeValue::String("dark".to_string()),
+                ),
+                (TableKey::Index(1), RuntimeValue::Number(3.0)),
+            ])),
+            true,
+        );
+
+        let interpolated = vars.interpolated();
+        assert_eq!(interpolated["HD"], "true");
+        assert_eq!(interpolated["SCALE"], "2");
+        assert_eq!(interpolated["RATIO"], "0.5");
+        assert_eq!(interpolated["CONFIG.theme"], "dark");
+        assert_eq!(interpolated["CONFIG.1"], "3");
+        assert!(!interpolated.contains_key("SECRET"));
+
+        // Setting a var again without opting in stops interpolating it.
+        vars.set("HD", RuntimeValue::Bool(false), false);
+        assert!(!vars.interpolated().contains_key("HD"));
+        assert_eq!(vars.get("HD"), Some(&RuntimeValue::Bool(false)));
+
+        assert_eq!(vars.remove("SCALE"), Some(RuntimeValue::Number(2.0)));
+        assert!(!vars.interpolated().contains_key("SCALE"));
+    }
+}
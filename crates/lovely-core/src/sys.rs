@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::ffi::{c_char, c_int, c_void, CString};
 use std::ptr;
 use std::slice;
@@ -17,6 +17,9 @@ pub type LuaFunc = unsafe extern "C" fn(*mut LuaState) -> c_int;
 pub const LUA_GLOBALSINDEX: c_int = -10002;
 pub const LUA_TNIL: c_int = 0;
 pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
 pub const fn lua_upvalueindex(i: c_int) -> c_int {
     // This is a macro in lua
     LUA_GLOBALSINDEX - i
@@ -73,6 +76,11 @@ generate! (LuaLib {
     pub unsafe extern "C" fn lua_pushcclosure(state: *mut LuaState, f: LuaFunc, n: c_int);
     pub unsafe extern "C" fn lua_tolstring(state: *mut LuaState, index: c_int, len: *mut usize) -> *const c_char;
     pub unsafe extern "C" fn lua_type(state: *mut LuaState, index: c_int) -> c_int;
+    pub unsafe extern "C" fn lua_typename(state: *mut LuaState, tp: c_int) -> *const c_char;
+    pub unsafe extern "C" fn lua_toboolean(state: *mut LuaState, index: c_int) -> c_int;
+    pub unsafe extern "C" fn lua_tonumber(state: *mut LuaState, index: c_int) -> f64;
+    pub unsafe extern "C" fn lua_next(state: *mut LuaState, index: c_int) -> c_int;
+    pub unsafe extern "C" fn lua_pushnil(state: *mut LuaState);
     pub unsafe extern "C" fn lua_pushstring(state: *mut LuaState, string: *const char);
     pub unsafe extern "C" fn lua_pushnumber(state: *mut LuaState, number: f64);
     pub unsafe extern "C" fn lua_pushboolean(state: *mut LuaState, b: c_int);
@@ -99,6 +107,11 @@ impl LuaLib {
             lua_pushcclosure: *library.get(b"lua_pushcclosure").unwrap(),
             lua_tolstring: *library.get(b"lua_tolstring").unwrap(),
             lua_type: *library.get(b"lua_type").unwrap(),
+            lua_typename: *library.get(b"lua_typename").unwrap(),
+            lua_toboolean: *library.get(b"lua_toboolean").unwrap(),
+            lua_tonumber: *library.get(b"lua_tonumber").unwrap(),
+            lua_next: *library.get(b"lua_next").unwrap(),
+            lua_pushnil: *library.get(b"lua_pushnil").unwrap(),
             lual_register: *library.get(b"luaL_register").unwrap(),
             lua_pushstring: *library.get(b"lua_pushstring").unwrap(),
             lua_pushnumber: *library.get(b"lua_pushnumber").unwrap(),
@@ -177,6 +190,38 @@ impl Pushable for bool {
     }
 }
 
+impl<P: Pushable> Pushable for [P] {
+    /// Push the values as a Lua array (1-indexed table).
+    unsafe fn push(&self, state: *mut LuaState) {
+        lua_createtable(state, self.len().try_into().unwrap(), 0);
+
+        for (i, val) in self.iter().enumerate() {
+            lua_pushnumber(state, (i + 1) as _);
+            val.push(state);
+            lua_settable(state, -3);
+        }
+    }
+}
+
+impl<P: Pushable> Pushable for Vec<P> {
+    unsafe fn push(&self, state: *mut LuaState) {
+        self.as_slice().push(state);
+    }
+}
+
+impl<P: Pushable> Pushable for BTreeMap<String, P> {
+    /// Push the entries as a Lua table with string keys.
+    unsafe fn push(&self, state: *mut LuaState) {
+        lua_createtable(state, 0, self.len().try_into().unwrap());
+
+        for (key, val) in self.iter() {
+            key.push(state);
+            val.push(state);
+            lua_settable(state, -3);
+        }
+    }
+}
+
 impl Pushable for LuaFunc {
     unsafe fn push(&self, state: *mut LuaState) {
         lua_pushcclosure(state, *self as _, 0);
@@ -255,6 +300,25 @@ pub unsafe fn preload_module<P: Pushable>(state: *mut LuaState, name: &'static s
     lua_settop(state, top);
 }
 
+/// Remove a module from `package.loaded`, so that the next `require` evaluates it again.
+///
+/// # Safety
+/// Directly interacts and mutates native Lua state.
+pub unsafe fn unload_module(state: *mut LuaState, name: &str) {
+    let name = CString::new(name).unwrap();
+    let top = lua_gettop(state);
+
+    lua_getfield(state, LUA_GLOBALSINDEX, c"package".as_ptr());
+    lua_getfield(state, -1, c"loaded".as_ptr());
+
+    // Growing the stack pushes nil.
+    lua_settop(state, top + 3);
+    lua_setfield(state, top + 2, name.as_ptr());
+
+    // Reset the stack.
+    lua_settop(state, top);
+}
+
 /// Load the provided buffer as a lua module with the specified name.
 /// # Safety
 /// Makes a lot of FFI calls, mutates internal C lua state.
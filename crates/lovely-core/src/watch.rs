@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use itertools::Itertools;
+use log::*;
+use walkdir::WalkDir;
+
+use crate::patch::table::PatchTable;
+use crate::patch::Patch;
+use crate::sys::{LuaState, LuaTable, Pushable};
+use crate::Lovely;
+
+/// How often the mod directory is polled for changes.
+pub const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification times of every file which can affect the patch table.
+type Snapshot = BTreeMap<PathBuf, SystemTime>;
+
+/// What changed during one or more reloads, handed to Lua through `lovely.poll_reload()`.
+#[derive(Debug, Default)]
+pub struct ReloadEvent {
+    // Changed files, relative to the mod directory.
+    pub files: Vec<String>,
+    // Targets patched by the reloaded mods, both before and after the reload.
+    pub targets: Vec<String>,
+    // Names of the module patches declared by the reloaded mods.
+    pub modules: Vec<String>,
+}
+
+impl ReloadEvent {
+    /// Fold a later reload into this one, so that no change is lost between two polls.
+    pub fn merge(&mut self, other: ReloadEvent) {
+        for (ours, theirs) in [
+            (&mut self.files, other.files),
+            (&mut self.targets, other.targets),
+            (&mut self.modules, other.modules),
+        ] {
+            ours.extend(theirs);
+            ours.sort();
+            ours.dedup();
+        }
+    }
+}
+
+impl Pushable for ReloadEvent {
+    unsafe fn push(&self, state: *mut LuaState) {
+        LuaTable::new()
+            .add_var("files", self.files.clone())
+            .add_var("targets", self.targets.clone())
+            .add_var("modules", self.modules.clone())
+            .push(state);
+    }
+}
+
+/// Collect the modification times of the patch files of every mod, the sources referenced by
+/// the loaded patches and the blacklist. Zip mods are tracked as a whole.
+fn snapshot(mod_dir: &Path, table: &PatchTable) -> Snapshot {
+    let mut files = vec![mod_dir.join("lovely").join("blacklist.txt")];
+
+    let entries = fs::read_dir(mod_dir).into_iter().flatten().filter_map(|x| x.ok());
+    for entry in entries {
+        let path = entry.path();
+        if path.is_file() && path.extension().is_some_and(|x| x == "zip") {
+            files.push(path);
+        } else if path.is_dir() && path.file_name().is_some_and(|x| x != "lovely") {
+            files.push(path.join("lovely.toml"));
+            files.push(path.join(".lovelyignore"));
+            files.extend(
+                WalkDir::new(path.join("lovely"))
+                    .into_iter()
+                    .filter_map(|x| x.ok())
+                    .map(|x| x.into_path())
+                    .filter(|x| x.extension().is_some_and(|x| x == "toml")),
+            );
+        }
+    }
+
+    for loaded in &table.patches {
+        // Sources are relative to the root of the mod, the first component of the patch path.
+        let Some(root) = loaded.path.components().next() else {
+            continue;
+        };
+        let root = mod_dir.join(root);
+        if !root.is_dir() {
+            continue;
+        }
+
+        match &loaded.patch {
+            Patch::Copy(x) => {
+                files.extend(x.sources.iter().flatten().map(|x| root.join(x)));
+            }
+            Patch::Module(x) => files.push(root.join(&x.source)),
+            _ => (),
+        }
+    }
+
+    files
+        .into_iter()
+        .filter_map(|x| {
+            let modified = fs::metadata(&x).and_then(|x| x.modified()).ok()?;
+            Some((x, modified))
+        })
+        .collect()
+}
+
+/// Files which were added, removed or modified between the two snapshots.
+fn changed_files(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+    old.keys()
+        .chain(new.keys())
+        .filter(|x| old.get(*x) != new.get(*x))
+        .unique()
+        .cloned()
+        .collect()
+}
+
+/// Collect the targets and module names of every patch declared by one of the provided mods.
+fn affected_by(table: &PatchTable, mods: &HashSet<OsString>) -> (HashSet<String>, HashSet<String>) {
+    let mut targets = HashSet::new();
+    let mut modules = HashSet::new();
+
+    let patches = table.patches.iter().filter(|x| {
+        x.path
+            .components()
+            .next()
+            .is_some_and(|x| mods.contains(x.as_os_str()))
+    });
+    for loaded in patches {
+        if let Some(target) = loaded.patch.target() {
+            targets.extend(target.names());
+        }
+        if let Patch::Module(x) = &loaded.patch {
+            modules.insert(x.name.clone());
+            targets.extend(x.before.clone());
+        }
+    }
+
+    (targets, modules)
+}
+
+/// Spawn a thread which polls the mod directory and reloads the patch table whenever a patch
+/// file or one of the sources it references changes.
+pub fn spawn(lovely: &'static Lovely, interval: Duration) {
+    let mod_dir = lovely.mod_dir.clone();
+
+    let result = thread::Builder::new()
+        .name("lovely-watch".into())
+        .spawn(move || {
+            let mut last = snapshot(&mod_dir, &lovely.patch_table.read().unwrap());
+
+            loop {
+                thread::sleep(interval);
+
+                let current = snapshot(&mod_dir, &lovely.patch_table.read().unwrap());
+                if current == last {
+                    continue;
+                }
+
+                let changed = changed_files(&last, &current);
+                let files = changed
+                    .iter()
+                    .map(|x| x.strip_prefix(&mod_dir).unwrap_or(x))
+                    .collect_vec();
+                let mods: HashSet<OsString> = files
+                    .iter()
+                    .filter_map(|x| x.components().next())
+                    .map(|x| x.as_os_str().to_os_string())
+                    .collect();
+
+                info!(
+                    "Detected changes to {}, reloading patches",
+                    files.iter().map(|x| format!("{x:?}")).join(", ")
+                );
+
+                let (mut targets, mut modules) =
+                    affected_by(&lovely.patch_table.read().unwrap(), &mods);
+                if let Err(e) = lovely.reload_patches() {
+                    error!("Failed to reload patches, keeping the previous ones: {e:?}");
+                    last = current;
+                    continue;
+                }
+
+                // The new table may reference sources the old one did not, so snapshot again
+                // to avoid picking those up as changes on the next poll.
+                let table = lovely.patch_table.read().unwrap();
+                let (new_targets, new_modules) = affected_by(&table, &mods);
+                targets.extend(new_targets);
+                modules.extend(new_modules);
+                last = snapshot(&mod_dir, &table);
+                drop(table);
+
+                let event = ReloadEvent {
+                    files: files
+                        .iter()
+                        .map(|x| x.to_string_lossy().replace('\\', "/"))
+                        .sorted()
+                        .collect(),
+                    targets: targets.into_iter().sorted().collect(),
+                    modules: modules.into_iter().sorted().collect(),
+                };
+                info!(
+                    "Reloaded patches, {} targets and {} modules are affected",
+                    event.targets.len(),
+                    event.modules.len()
+                );
+
+                let mut pending = lovely.reload_event.write().unwrap();
+                match pending.as_mut() {
+                    Some(x) => x.merge(event),
+                    None => *pending = Some(event),
+                }
+            }
+        });
+
+    if let Err(e) = result {
+        error!("Failed to spawn the patch watcher thread: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_files_detects_added_removed_and_modified() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+
+        let old: Snapshot = [
+            (PathBuf::from("a/lovely.toml"), t0),
+            (PathBuf::from("b/lovely.toml"), t0),
+            (PathBuf::from("c/lovely.toml"), t0),
+        ]
+        .into();
+        let new: Snapshot = [
+            (PathBuf::from("a/lovely.toml"), t0),
+            (PathBuf::from("b/lovely.toml"), t1),
+            (PathBuf::from("d/lovely.toml"), t0),
+        ]
+        .into();
+
+        let mut changed = changed_files(&old, &new);
+        changed.sort();
+        assert_eq!(
+            changed,
+            vec![
+                PathBuf::from("b/lovely.toml"),
+                PathBuf::from("c/lovely.toml"),
+                PathBuf::from("d/lovely.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_events_are_deduplicated() {
+        let mut event = ReloadEvent {
+            files: vec!["a/lovely.toml".into()],
+            targets: vec!["main.lua".into()],
+            modules: vec![],
+        };
+        event.merge(ReloadEvent {
+            files: vec!["a/lovely.toml".into(), "a/lib.lua".into()],
+            targets: vec!["main.lua".into(), "card.lua".into()],
+            modules: vec!["lib".into()],
+        });
+
+        assert_eq!(event.files, vec!["a/lib.lua", "a/lovely.toml"]);
+        assert_eq!(event.targets, vec!["card.lua", "main.lua"]);
+        assert_eq!(event.modules, vec!["lib"]);
+    }
+}